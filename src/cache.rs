@@ -0,0 +1,249 @@
+use log::{info, trace};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+/// Hit ratio is logged after this many lookups
+const LOG_EVERY: u64 = 100;
+
+#[derive(Debug, Clone)]
+struct CacheEntry<V> {
+    value: V,
+    inserted: Instant,
+    /// Value of `Cache::clock` when last looked up or inserted
+    last_used: u64,
+}
+
+/// Least recently used cache, where entries also expire a fixed time after being added
+#[derive(Debug, Clone)]
+pub struct Cache<K, V> {
+    /// Used in logs and metrics
+    name: &'static str,
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<K, CacheEntry<V>>,
+    /// Incremented on every use, to find the least recently used entry
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    /// Number of lookups when the hit ratio is next logged
+    next_log: u64,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone + std::fmt::Debug,
+    V: Clone,
+{
+    pub fn new(name: &'static str, capacity: usize, ttl_ms: u32) -> Cache<K, V> {
+        Cache {
+            name,
+            capacity,
+            ttl: Duration::from_millis(ttl_ms.into()),
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            next_log: LOG_EVERY,
+        }
+    }
+
+    /// Change size and expiry time, e.g after config is reloaded
+    pub fn configure(&mut self, capacity: usize, ttl_ms: u32) {
+        self.capacity = capacity;
+        self.ttl = Duration::from_millis(ttl_ms.into());
+        self.remove_expired(Instant::now());
+        while self.entries.len() > self.capacity {
+            self.remove_oldest();
+        }
+    }
+
+    /// Fraction of lookups which found an entry
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+
+    /// Look up entry, if present and not expired
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.get_at(key, Instant::now())
+    }
+
+    /// Look up entry, if present and not expired at the given time
+    fn get_at(&mut self, key: &K, now: Instant) -> Option<V> {
+        self.clock += 1;
+        let ttl = self.ttl;
+        let found = match self.entries.get_mut(key) {
+            Some(e) if now.saturating_duration_since(e.inserted) < ttl => {
+                e.last_used = self.clock;
+                Some(e.value.clone())
+            }
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let result = if found.is_some() {
+            self.hits += 1;
+            "hit"
+        } else {
+            self.misses += 1;
+            "miss"
+        };
+        trace!("{} cache {} for {:?}", self.name, result, key);
+        metrics::CACHE_LOOKUPS
+            .with_label_values(&[self.name, result])
+            .inc();
+        if self.hits + self.misses >= self.next_log {
+            self.next_log += LOG_EVERY;
+            info!(
+                "{} cache hit ratio {:.0}% ({} hits, {} misses, {} entries)",
+                self.name,
+                self.hit_ratio() * 100.0,
+                self.hits,
+                self.misses,
+                self.entries.len()
+            );
+        }
+        found
+    }
+
+    /// Add or replace entry, making room by removing expired entries or else the least recently used
+    pub fn insert(&mut self, key: K, value: V) {
+        self.insert_at(key, value, Instant::now())
+    }
+
+    /// Add or replace entry, as inserted at the given time
+    fn insert_at(&mut self, key: K, value: V, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.remove_expired(now);
+            if self.entries.len() >= self.capacity {
+                self.remove_oldest();
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                inserted: now,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, e| now.saturating_duration_since(e.inserted) < ttl);
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(k, _)| k.clone());
+        if let Some(k) = oldest {
+            self.entries.remove(&k);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize) -> Cache<u32, &'static str> {
+        Cache::new("test", capacity, 60_000)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut c = cache(2);
+        c.insert(1, "one");
+        c.insert(2, "two");
+        // Looking up 1 makes 2 the least recently used
+        assert_eq!(c.get(&1), Some("one"));
+        c.insert(3, "three");
+        assert_eq!(c.get(&2), None);
+        assert_eq!(c.get(&1), Some("one"));
+        assert_eq!(c.get(&3), Some("three"));
+    }
+
+    #[test]
+    fn replacing_does_not_evict() {
+        let mut c = cache(2);
+        c.insert(1, "one");
+        c.insert(2, "two");
+        c.insert(1, "uno");
+        assert_eq!(c.get(&1), Some("uno"));
+        assert_eq!(c.get(&2), Some("two"));
+    }
+
+    #[test]
+    fn entries_expire() {
+        let start = Instant::now();
+        let mut c = Cache::new("test", 2, 10);
+        c.insert_at(1, "one", start);
+        assert_eq!(c.get_at(&1, start + Duration::from_millis(9)), Some("one"));
+        assert_eq!(c.get_at(&1, start + Duration::from_millis(10)), None);
+        assert!(c.entries.is_empty());
+    }
+
+    #[test]
+    fn expired_entries_make_room_first() {
+        let start = Instant::now();
+        let later = start + Duration::from_millis(250);
+        let mut c = Cache::new("test", 2, 200);
+        c.insert_at(1, "one", start);
+        c.insert_at(2, "two", later);
+        // 1 was used last, but has expired so goes first
+        c.get_at(&1, start);
+        c.insert_at(3, "three", later);
+        assert_eq!(c.get_at(&2, later), Some("two"));
+        assert_eq!(c.get_at(&3, later), Some("three"));
+    }
+
+    #[test]
+    fn size_zero_disables() {
+        let mut c = cache(0);
+        c.insert(1, "one");
+        assert_eq!(c.get(&1), None);
+        assert!(c.entries.is_empty());
+    }
+
+    #[test]
+    fn shrinking_evicts_least_recently_used() {
+        let mut c = cache(3);
+        c.insert(1, "one");
+        c.insert(2, "two");
+        c.insert(3, "three");
+        c.get(&1);
+        c.configure(1, 60_000);
+        assert_eq!(c.get(&1), Some("one"));
+        assert_eq!(c.get(&2), None);
+        assert_eq!(c.get(&3), None);
+    }
+
+    #[test]
+    fn hit_ratio() {
+        let mut c = cache(2);
+        assert_eq!(c.hit_ratio(), 0.0);
+        c.insert(1, "one");
+        c.get(&1);
+        c.get(&2);
+        assert_eq!(c.hit_ratio(), 0.5);
+    }
+}
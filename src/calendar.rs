@@ -0,0 +1,857 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use chrono::{
+    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use ical::parser::ical::component::{IcalCalendar, IcalTimeZoneTransition};
+use ical::property::Property;
+use ical::IcalParser;
+
+use crate::common::{duration_as_millis, ClientResult};
+
+/// Yearly rule for when a `VTIMEZONE` observance starts, e.g "FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU"
+#[derive(Debug, Clone, PartialEq)]
+struct YearlyRule {
+    month: u32,
+    /// Week of the month, counting from the end if negative
+    week: i32,
+    day: Weekday,
+    until: Option<NaiveDateTime>,
+}
+
+/// `STANDARD` or `DAYLIGHT` part of a `VTIMEZONE`
+#[derive(Debug, Clone, PartialEq)]
+struct Observance {
+    /// UTC offsets in seconds before and after it starts
+    offset_from: i32,
+    offset_to: i32,
+    /// Local time it first starts
+    start: NaiveDateTime,
+    /// How it repeats, if it does
+    rule: Option<YearlyRule>,
+}
+
+impl Observance {
+    /// Latest time it started at or before given local time
+    fn last_start(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        if t < self.start {
+            return None;
+        }
+        let r = match &self.rule {
+            Some(r) => r,
+            None => return Some(self.start),
+        };
+        [t.year(), t.year() - 1]
+            .iter()
+            .filter_map(|y| nth_weekday(*y, r.month, r.week, r.day))
+            .map(|d| d.and_time(self.start.time()))
+            .find(|s| *s <= t && *s >= self.start && r.until.map(|u| *s <= u).unwrap_or(true))
+    }
+}
+
+/// Date of the `week`th given weekday of a month, counting from the end if negative
+fn nth_weekday(year: i32, month: u32, week: i32, day: Weekday) -> Option<NaiveDate> {
+    let day = i64::from(day.num_days_from_monday());
+    let date = if week > 0 {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let offset = (7 + day - i64::from(first.weekday().num_days_from_monday())) % 7;
+        first + Duration::days(offset + 7 * i64::from(week - 1))
+    } else {
+        let next = if month == 12 {
+            NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+        } else {
+            NaiveDate::from_ymd_opt(year, month + 1, 1)?
+        };
+        let last = next.pred_opt()?;
+        let offset = (7 + i64::from(last.weekday().num_days_from_monday()) - day) % 7;
+        last - Duration::days(offset + 7 * i64::from(-week - 1))
+    };
+    Some(date).filter(|d| d.month() == month)
+}
+
+/// Time zone of an event's times
+#[derive(Debug, Clone, PartialEq)]
+enum Zone {
+    Utc,
+    Named(Tz),
+    /// From a `VTIMEZONE` in the calendar, e.g for Windows names like "GMT Standard Time"
+    Defined(Vec<Observance>),
+    /// System time zone, for times without one when no `timezone` is configured
+    System,
+}
+
+impl Zone {
+    /// UTC time of given local time in this zone
+    fn to_utc(&self, t: NaiveDateTime) -> DateTime<Utc> {
+        match self {
+            Zone::Utc => Utc.from_utc_datetime(&t),
+            Zone::Named(tz) => local_to_utc(tz, t),
+            Zone::System => local_to_utc(&Local, t),
+            Zone::Defined(observances) => {
+                let offset = observances
+                    .iter()
+                    .filter_map(|o| o.last_start(t).map(|s| (s, o.offset_to)))
+                    .max_by_key(|(s, _)| *s)
+                    .map(|(_, offset)| offset)
+                    .or_else(|| {
+                        observances
+                            .iter()
+                            .min_by_key(|o| o.start)
+                            .map(|o| o.offset_from)
+                    })
+                    .unwrap_or(0);
+                Utc.from_utc_datetime(&(t - Duration::seconds(offset.into())))
+            }
+        }
+    }
+}
+
+/// UTC time of given local time. The earlier time if it is ambiguous as the clocks went back, and
+/// using the offset from before if it was skipped as they went forward
+fn local_to_utc<Z: TimeZone>(zone: &Z, t: NaiveDateTime) -> DateTime<Utc> {
+    match zone.from_local_datetime(&t).earliest() {
+        Some(d) => d.with_timezone(&Utc),
+        None => local_to_utc(zone, t - Duration::hours(1)) + Duration::hours(1),
+    }
+}
+
+/// How an event repeats, from its `RRULE`. Only daily and weekly rules are understood
+#[derive(Debug, Clone, PartialEq)]
+struct Recurrence {
+    weekly: bool,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<DateTime<Utc>>,
+    /// Days of the week for weekly rules. If empty, the day of the first occurrence
+    by_day: Vec<Weekday>,
+}
+
+/// Event from a calendar file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalendarEvent {
+    pub summary: String,
+    /// Start of the first occurrence, in `zone`
+    start: NaiveDateTime,
+    /// Length of each occurrence
+    length: Duration,
+    zone: Zone,
+    recurrence: Option<Recurrence>,
+    /// Start times of occurrences which were cancelled or moved
+    exceptions: Vec<DateTime<Utc>>,
+}
+
+impl CalendarEvent {
+    /// End of the occurrence happening at given time, if any. Repeats keep the same local time
+    /// in the event's time zone, even when the clocks change
+    pub fn occurrence_end(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let r = match &self.recurrence {
+            Some(r) => r,
+            None => {
+                let start = self.zone.to_utc(self.start);
+                return Some(start + self.length).filter(|end| start <= t && t < *end);
+            }
+        };
+
+        // Days of the week which occurrences fall on, as offsets from Monday
+        let mut days: Vec<i64> = if r.weekly && !r.by_day.is_empty() {
+            r.by_day
+                .iter()
+                .map(|d| i64::from(d.num_days_from_monday()))
+                .collect()
+        } else {
+            vec![i64::from(self.start.weekday().num_days_from_monday())]
+        };
+        days.sort();
+        days.dedup();
+        let first_monday =
+            self.start.date() - Duration::days(self.start.weekday().num_days_from_monday().into());
+
+        let mut seen = 0;
+        for period in 0.. {
+            let starts: Vec<NaiveDateTime> = if r.weekly {
+                let monday = first_monday + Duration::weeks(period * i64::from(r.interval));
+                days.iter()
+                    .map(|d| (monday + Duration::days(*d)).and_time(self.start.time()))
+                    .filter(|s| *s >= self.start)
+                    .collect()
+            } else {
+                vec![self.start + Duration::days(period * i64::from(r.interval))]
+            };
+            for s in starts {
+                let s = self.zone.to_utc(s);
+                let finished = s > t
+                    || r.until.map(|u| s > u).unwrap_or(false)
+                    || r.count.map(|c| seen >= c).unwrap_or(false);
+                if finished {
+                    return None;
+                }
+                seen += 1;
+                if t < s + self.length && !self.exceptions.contains(&s) {
+                    return Some(s + self.length);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// First property with given name
+fn property<'a>(props: &'a [Property], name: &str) -> Option<&'a Property> {
+    props.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// Value of the first property with given name
+fn value<'a>(props: &'a [Property], name: &str) -> Option<&'a str> {
+    property(props, name).and_then(|p| p.value.as_deref())
+}
+
+/// First value of a property's parameter
+fn param<'a>(p: &'a Property, name: &str) -> Option<&'a str> {
+    p.params
+        .as_ref()?
+        .iter()
+        .find(|(n, _)| n == name)
+        .and_then(|(_, values)| values.first())
+        .map(|v| v.as_str())
+}
+
+/// Parse UTC offset, e.g "+0100" or "-0530", into seconds
+fn parse_offset(value: &str) -> Option<i32> {
+    let sign = match value.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let digits = &value[1..];
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let part = |i: usize| {
+        digits
+            .get(i..i + 2)
+            .map_or(Some(0), |d| d.parse::<i32>().ok())
+    };
+    Some(sign * (part(0)? * 3600 + part(2)? * 60 + part(4)?))
+}
+
+/// Parse `DURATION` value, e.g "PT1H30M" or "P1D"
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.trim_start_matches(&['+', 'P'][..]).chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => in_time = true,
+            _ => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                total = total
+                    + match (c, in_time) {
+                        ('W', false) => Duration::weeks(n),
+                        ('D', false) => Duration::days(n),
+                        ('H', true) => Duration::hours(n),
+                        ('M', true) => Duration::minutes(n),
+                        ('S', true) => Duration::seconds(n),
+                        _ => return None,
+                    };
+            }
+        }
+    }
+    Some(total)
+}
+
+/// Parse `BYDAY` entry, e.g "MO"
+fn parse_weekday(value: &str) -> Option<Weekday> {
+    Some(match value {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+/// Split `RRULE` value into its parts
+fn rule_parts(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter_map(|part| {
+            let mut kv = part.splitn(2, '=');
+            Some((kv.next()?.to_uppercase(), kv.next()?.to_uppercase()))
+        })
+        .collect()
+}
+
+/// Parse date-time without a time zone, e.g "20240101T090000"
+fn parse_naive(value: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S").ok()
+}
+
+/// Parse a `VTIMEZONE` observance
+fn parse_observance(t: &IcalTimeZoneTransition) -> Option<Observance> {
+    let props = &t.properties;
+    let rule = match value(props, "RRULE") {
+        Some(v) => {
+            let parts = rule_parts(v);
+            if parts.get("FREQ").map(|f| f.as_str()) != Some("YEARLY") {
+                return None;
+            }
+            let by_day = parts.get("BYDAY")?;
+            let (week, day) = by_day.split_at(by_day.len().checked_sub(2)?);
+            Some(YearlyRule {
+                month: parts.get("BYMONTH")?.parse().ok()?,
+                week: week.parse().ok()?,
+                day: parse_weekday(day)?,
+                until: match parts.get("UNTIL") {
+                    Some(u) => Some(parse_naive(u)?),
+                    None => None,
+                },
+            })
+        }
+        None => None,
+    };
+    Some(Observance {
+        offset_from: parse_offset(value(props, "TZOFFSETFROM")?)?,
+        offset_to: parse_offset(value(props, "TZOFFSETTO")?)?,
+        start: parse_naive(value(props, "DTSTART")?)?,
+        rule,
+    })
+}
+
+/// Time zones for reading the times of a calendar's events
+struct Zones {
+    /// From the calendar's `VTIMEZONE` components, by `TZID`
+    defined: HashMap<String, Zone>,
+    /// For times without a time zone
+    floating: Zone,
+}
+
+impl Zones {
+    fn new(cal: &IcalCalendar, floating: &Zone, problems: &mut Vec<String>) -> Zones {
+        let mut defined = HashMap::new();
+        for tz in &cal.timezones {
+            let id = match value(&tz.properties, "TZID") {
+                Some(id) => id,
+                None => continue,
+            };
+            let observances: Option<Vec<Observance>> =
+                tz.transitions.iter().map(parse_observance).collect();
+            let location = value(&tz.properties, "X-LIC-LOCATION").and_then(|l| l.parse().ok());
+            match (observances, location) {
+                (Some(o), _) if !o.is_empty() => {
+                    defined.insert(id.to_string(), Zone::Defined(o));
+                }
+                (_, Some(tz)) => {
+                    defined.insert(id.to_string(), Zone::Named(tz));
+                }
+                _ => problems.push(format!("time zone {:?} is not understood", id)),
+            }
+        }
+        Zones {
+            defined,
+            floating: floating.clone(),
+        }
+    }
+
+    /// Zone for a `TZID`, preferring a known IANA name over the calendar's definition
+    fn get(&self, tzid: &str) -> Option<Zone> {
+        match tzid.trim_start_matches('/').parse::<Tz>() {
+            Ok(tz) => Some(Zone::Named(tz)),
+            Err(_) => self.defined.get(tzid).cloned(),
+        }
+    }
+
+    /// Local times and their zone from a date or date-time property like `DTSTART` or `EXDATE`.
+    /// Dates are midnight in the floating zone
+    fn times(&self, p: &Property) -> Result<(Vec<NaiveDateTime>, Zone), String> {
+        let raw = p.value.as_deref().unwrap_or("");
+        let mut zone = match param(p, "TZID") {
+            Some(tzid) => self
+                .get(tzid)
+                .ok_or_else(|| format!("unknown time zone {:?}", tzid))?,
+            None => self.floating.clone(),
+        };
+        let mut times = vec![];
+        for v in raw.split(',').map(str::trim) {
+            let t = if v.len() == 8 {
+                NaiveDate::parse_from_str(v, "%Y%m%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            } else {
+                if v.ends_with('Z') {
+                    zone = Zone::Utc;
+                }
+                parse_naive(v)
+            };
+            times.push(t.ok_or_else(|| format!("{} {:?} is not understood", p.name, v))?);
+        }
+        Ok((times, zone))
+    }
+
+    /// Single time from a property
+    fn time(&self, p: &Property) -> Result<(NaiveDateTime, Zone), String> {
+        let (times, zone) = self.times(p)?;
+        match times.first() {
+            Some(t) => Ok((*t, zone)),
+            None => Err(format!("{} is empty", p.name)),
+        }
+    }
+}
+
+/// Parse `RRULE` value, or give the reason it is not understood
+fn parse_rrule(value: &str, zone: &Zone) -> Result<Recurrence, String> {
+    let mut r = Recurrence {
+        weekly: false,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: vec![],
+    };
+    for (k, v) in rule_parts(value) {
+        let bad = || format!("{}={} is not understood", k, v);
+        match k.as_str() {
+            "FREQ" if v == "DAILY" => r.weekly = false,
+            "FREQ" if v == "WEEKLY" => r.weekly = true,
+            "INTERVAL" => r.interval = v.parse().ok().filter(|i| *i > 0).ok_or_else(bad)?,
+            "COUNT" => r.count = Some(v.parse().map_err(|_| bad())?),
+            "UNTIL" if v.len() == 8 => {
+                // Includes the whole day
+                let day = NaiveDate::parse_from_str(&v, "%Y%m%d").map_err(|_| bad())?;
+                let end = day.and_hms_opt(23, 59, 59).ok_or_else(bad)?;
+                r.until = Some(zone.to_utc(end));
+            }
+            "UNTIL" if v.ends_with('Z') => {
+                r.until = Some(Zone::Utc.to_utc(parse_naive(&v).ok_or_else(bad)?))
+            }
+            "UNTIL" => r.until = Some(zone.to_utc(parse_naive(&v).ok_or_else(bad)?)),
+            "BYDAY" => {
+                r.by_day = v
+                    .split(',')
+                    .map(parse_weekday)
+                    .collect::<Option<Vec<Weekday>>>()
+                    .ok_or_else(bad)?
+            }
+            "WKST" => (),
+            _ => return Err(bad()),
+        }
+    }
+    Ok(r)
+}
+
+/// Build event from its properties, noting any problems which mean it is not fully understood
+fn parse_event(
+    props: &[Property],
+    zones: &Zones,
+    problems: &mut Vec<String>,
+) -> Result<CalendarEvent, String> {
+    let summary = value(props, "SUMMARY").unwrap_or_default().to_string();
+    let start_property = property(props, "DTSTART").ok_or("no DTSTART")?;
+    let (start, zone) = zones.time(start_property)?;
+    let start_utc = zone.to_utc(start);
+    let end = if let Some(p) = property(props, "DTEND") {
+        let (end, end_zone) = zones.time(p)?;
+        end_zone.to_utc(end)
+    } else if let Some(v) = value(props, "DURATION") {
+        start_utc
+            + parse_duration(v).ok_or_else(|| format!("DURATION {:?} is not understood", v))?
+    } else if start_property.value.as_deref().map(str::len) == Some(8) {
+        // All day event
+        zone.to_utc(start + Duration::days(1))
+    } else {
+        return Err("no DTEND or DURATION".into());
+    };
+    if end <= start_utc {
+        return Err("ends before it starts".into());
+    }
+
+    let recurrence = match value(props, "RRULE") {
+        Some(v) => match parse_rrule(v, &zone) {
+            Ok(r) => Some(r),
+            Err(reason) => {
+                problems.push(format!(
+                    "{:?} repeats with unsupported rule {:?} ({}), so only its first occurrence is used",
+                    summary, v, reason
+                ));
+                None
+            }
+        },
+        None => None,
+    };
+    let mut exceptions = vec![];
+    for p in props
+        .iter()
+        .filter(|p| p.name.eq_ignore_ascii_case("EXDATE"))
+    {
+        let (times, z) = zones.times(p)?;
+        exceptions.extend(times.into_iter().map(|t| z.to_utc(t)));
+    }
+
+    Ok(CalendarEvent {
+        summary,
+        start,
+        length: end - start_utc,
+        zone,
+        recurrence,
+        exceptions,
+    })
+}
+
+/// Read events from iCalendar data, along with problems with any which could not be fully
+/// understood. Times without a time zone are taken to be in `floating`
+fn parse_ics<R: BufRead>(
+    reader: R,
+    floating: &Zone,
+) -> ClientResult<(Vec<CalendarEvent>, Vec<String>)> {
+    let mut events = vec![];
+    let mut problems = vec![];
+    for cal in IcalParser::new(reader) {
+        let cal = cal?;
+        let zones = Zones::new(&cal, floating, &mut problems);
+        // Events by UID, and changes to single occurrences of repeating ones
+        let mut by_uid: HashMap<String, usize> = HashMap::new();
+        let mut overrides = vec![];
+
+        for e in &cal.events {
+            let props = &e.properties;
+            let summary = value(props, "SUMMARY").unwrap_or_default();
+            let cancelled = value(props, "STATUS")
+                .map(|s| s.eq_ignore_ascii_case("CANCELLED"))
+                .unwrap_or(false);
+            let uid = value(props, "UID").map(|u| u.to_string());
+            let recurrence_id = match property(props, "RECURRENCE-ID") {
+                Some(p) => match zones.time(p) {
+                    Ok((t, z)) => Some(z.to_utc(t)),
+                    Err(reason) => {
+                        problems.push(format!("skipping {:?}: {}", summary, reason));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            if cancelled && recurrence_id.is_none() {
+                continue;
+            }
+
+            let event = if cancelled {
+                None
+            } else {
+                match parse_event(props, &zones, &mut problems) {
+                    Ok(mut event) => {
+                        if recurrence_id.is_some() {
+                            // Replaces one occurrence, so does not repeat itself
+                            event.recurrence = None;
+                        }
+                        Some(event)
+                    }
+                    Err(reason) => {
+                        problems.push(format!("skipping {:?}: {}", summary, reason));
+                        continue;
+                    }
+                }
+            };
+            match (recurrence_id, uid) {
+                (Some(original), Some(uid)) => overrides.push((uid, original, event)),
+                (None, Some(uid)) => {
+                    by_uid.insert(uid, events.len());
+                    events.extend(event);
+                }
+                _ => events.extend(event),
+            }
+        }
+
+        for (uid, original, event) in overrides {
+            if let Some(i) = by_uid.get(&uid) {
+                events[*i].exceptions.push(original);
+            }
+            events.extend(event);
+        }
+    }
+    Ok((events, problems))
+}
+
+/// Read events from `.ics` file, along with problems with any which could not be fully understood
+fn load_ics(path: &Path, floating: &Zone) -> ClientResult<(Vec<CalendarEvent>, Vec<String>)> {
+    parse_ics(BufReader::new(File::open(path)?), floating)
+}
+
+/// Events from a set of calendar files, re-read periodically
+#[derive(Debug, Default)]
+pub struct Calendars {
+    events: HashMap<PathBuf, Vec<CalendarEvent>>,
+    last_refresh: Option<Instant>,
+}
+
+impl Calendars {
+    /// Re-read files if `refresh_ms` has passed, or the list of files changed. Times without a
+    /// time zone are taken to be in `timezone`, or the system time zone if not given
+    pub fn refresh(&mut self, paths: &[PathBuf], refresh_ms: u32, timezone: Option<Tz>) {
+        let changed =
+            paths.len() != self.events.len() || paths.iter().any(|p| !self.events.contains_key(p));
+        let due = self
+            .last_refresh
+            .map(|t| duration_as_millis(t.elapsed()) > refresh_ms.into())
+            .unwrap_or(true);
+        if !(changed || due) {
+            return;
+        }
+
+        let floating = timezone.map(Zone::Named).unwrap_or(Zone::System);
+        self.events.retain(|p, _| paths.contains(p));
+        for p in paths {
+            match load_ics(p, &floating) {
+                Ok((events, problems)) => {
+                    if self.events.get(p) != Some(&events) {
+                        info!("Read {} events from {}", events.len(), p.display());
+                        for problem in problems {
+                            warn!("Calendar {}: {}", p.display(), problem);
+                        }
+                    }
+                    self.events.insert(p.clone(), events);
+                }
+                Err(e) => {
+                    // Keep the events read last time
+                    warn!("Could not read calendar {}: {}", p.display(), e);
+                    self.events.entry(p.clone()).or_default();
+                }
+            }
+        }
+        self.last_refresh = Some(Instant::now());
+    }
+
+    /// Event happening at given time which matches the filter, along with when it ends. If
+    /// several match, the one ending last
+    pub fn active<F>(&self, t: DateTime<Utc>, filter: F) -> Option<(&CalendarEvent, DateTime<Utc>)>
+    where
+        F: Fn(&CalendarEvent) -> bool,
+    {
+        self.events
+            .values()
+            .flatten()
+            .filter(|e| filter(e))
+            .filter_map(|e| e.occurrence_end(t).map(|end| (e, end)))
+            .max_by_key(|(_, end)| *end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap())
+    }
+
+    fn parse(events: &str) -> (Vec<CalendarEvent>, Vec<String>) {
+        let ics = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{}END:VCALENDAR\r\n",
+            events.replace('\n', "\r\n")
+        );
+        parse_ics(ics.as_bytes(), &Zone::Named(chrono_tz::Asia::Tokyo)).unwrap()
+    }
+
+    fn active(events: &[CalendarEvent], t: &str) -> Option<DateTime<Utc>> {
+        events.iter().find_map(|e| e.occurrence_end(utc(t)))
+    }
+
+    #[test]
+    fn windows_time_zone_from_vtimezone() {
+        let (events, problems) = parse(
+            "BEGIN:VTIMEZONE
+TZID:GMT Standard Time
+BEGIN:STANDARD
+DTSTART:16010101T020000
+TZOFFSETFROM:+0100
+TZOFFSETTO:+0000
+RRULE:FREQ=YEARLY;INTERVAL=1;BYDAY=-1SU;BYMONTH=10
+END:STANDARD
+BEGIN:DAYLIGHT
+DTSTART:16010101T010000
+TZOFFSETFROM:+0000
+TZOFFSETTO:+0100
+RRULE:FREQ=YEARLY;INTERVAL=1;BYDAY=-1SU;BYMONTH=3
+END:DAYLIGHT
+END:VTIMEZONE
+BEGIN:VEVENT
+UID:summer
+SUMMARY:Summer
+DTSTART;TZID=GMT Standard Time:20240701T100000
+DTEND;TZID=GMT Standard Time:20240701T110000
+END:VEVENT
+BEGIN:VEVENT
+UID:winter
+SUMMARY:Winter
+DTSTART;TZID=GMT Standard Time:20240115T100000
+DTEND;TZID=GMT Standard Time:20240115T110000
+END:VEVENT
+",
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            active(&events, "2024-07-01 09:30"),
+            Some(utc("2024-07-01 10:00"))
+        );
+        assert_eq!(active(&events, "2024-07-01 10:30"), None);
+        assert_eq!(active(&events, "2024-01-15 09:30"), None);
+        assert_eq!(
+            active(&events, "2024-01-15 10:30"),
+            Some(utc("2024-01-15 11:00"))
+        );
+    }
+
+    #[test]
+    fn named_utc_and_floating_times() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT
+SUMMARY:New York
+DTSTART;TZID=America/New_York:20240701T100000
+DURATION:PT1H
+END:VEVENT
+BEGIN:VEVENT
+SUMMARY:UTC
+DTSTART:20240702T100000Z
+DTEND:20240702T110000Z
+END:VEVENT
+BEGIN:VEVENT
+SUMMARY:Floating
+DTSTART:20240703T100000
+DTEND:20240703T110000
+END:VEVENT
+",
+        );
+        assert_eq!(
+            active(&events, "2024-07-01 14:30"),
+            Some(utc("2024-07-01 15:00"))
+        );
+        assert_eq!(
+            active(&events, "2024-07-02 10:30"),
+            Some(utc("2024-07-02 11:00"))
+        );
+        // Floating times are in the given zone, here 9 hours ahead
+        assert_eq!(
+            active(&events, "2024-07-03 01:30"),
+            Some(utc("2024-07-03 02:00"))
+        );
+        assert_eq!(active(&events, "2024-07-03 10:30"), None);
+    }
+
+    #[test]
+    fn weekly_with_exceptions_and_overrides() {
+        let (events, problems) = parse(
+            "BEGIN:VEVENT
+UID:standup
+SUMMARY:Standup
+DTSTART;TZID=Europe/London:20240101T100000
+DTEND;TZID=Europe/London:20240101T110000
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=10
+EXDATE;TZID=Europe/London:20240103T100000
+END:VEVENT
+BEGIN:VEVENT
+UID:standup
+RECURRENCE-ID;TZID=Europe/London:20240108T100000
+SUMMARY:Standup (moved)
+DTSTART;TZID=Europe/London:20240108T150000
+DTEND;TZID=Europe/London:20240108T160000
+END:VEVENT
+BEGIN:VEVENT
+UID:standup
+RECURRENCE-ID;TZID=Europe/London:20240110T100000
+SUMMARY:Standup
+STATUS:CANCELLED
+DTSTART;TZID=Europe/London:20240110T100000
+DTEND;TZID=Europe/London:20240110T110000
+END:VEVENT
+",
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(
+            active(&events, "2024-01-01 10:30"),
+            Some(utc("2024-01-01 11:00"))
+        );
+        assert_eq!(active(&events, "2024-01-03 10:30"), None);
+        assert_eq!(active(&events, "2024-01-08 10:30"), None);
+        assert_eq!(
+            active(&events, "2024-01-08 15:30"),
+            Some(utc("2024-01-08 16:00"))
+        );
+        assert_eq!(active(&events, "2024-01-10 10:30"), None);
+        assert_eq!(
+            active(&events, "2024-01-15 10:30"),
+            Some(utc("2024-01-15 11:00"))
+        );
+        // Tenth and last occurrence
+        assert_eq!(
+            active(&events, "2024-01-31 10:30"),
+            Some(utc("2024-01-31 11:00"))
+        );
+        assert_eq!(active(&events, "2024-02-05 10:30"), None);
+    }
+
+    #[test]
+    fn repeats_keep_local_time_when_clocks_change() {
+        let (events, _) = parse(
+            "BEGIN:VEVENT
+SUMMARY:Daily
+DTSTART;TZID=Europe/London:20240330T090000
+DTEND;TZID=Europe/London:20240330T093000
+RRULE:FREQ=DAILY;UNTIL=20240402
+END:VEVENT
+",
+        );
+        assert_eq!(
+            active(&events, "2024-03-30 09:10"),
+            Some(utc("2024-03-30 09:30"))
+        );
+        // British Summer Time from 31 March
+        assert_eq!(
+            active(&events, "2024-04-01 08:10"),
+            Some(utc("2024-04-01 08:30"))
+        );
+        assert_eq!(active(&events, "2024-04-01 09:10"), None);
+        assert_eq!(
+            active(&events, "2024-04-02 08:10"),
+            Some(utc("2024-04-02 08:30"))
+        );
+        assert_eq!(active(&events, "2024-04-03 08:10"), None);
+    }
+
+    #[test]
+    fn unsupported_rule_is_reported() {
+        let (events, problems) = parse(
+            "BEGIN:VEVENT
+SUMMARY:Monthly review
+DTSTART:20240105T100000Z
+DTEND:20240105T110000Z
+RRULE:FREQ=MONTHLY;BYDAY=1FR
+END:VEVENT
+",
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("MONTHLY"), "{}", problems[0]);
+        assert_eq!(
+            active(&events, "2024-01-05 10:30"),
+            Some(utc("2024-01-05 11:00"))
+        );
+        assert_eq!(active(&events, "2024-02-02 10:30"), None);
+    }
+
+    #[test]
+    fn nth_weekday_of_month() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+        assert_eq!(nth_weekday(2024, 3, -1, Weekday::Sun), date(2024, 3, 31));
+        assert_eq!(nth_weekday(2024, 10, -1, Weekday::Sun), date(2024, 10, 27));
+        assert_eq!(nth_weekday(2024, 3, 2, Weekday::Sun), date(2024, 3, 10));
+        assert_eq!(nth_weekday(2024, 12, -1, Weekday::Tue), date(2024, 12, 31));
+        assert_eq!(nth_weekday(2024, 2, 5, Weekday::Mon), None);
+    }
+}
@@ -1,26 +1,82 @@
 use failure::format_err;
 
-use log::{debug, info, trace};
+use chrono::{Datelike, Utc};
+use log::{debug, info, trace, warn};
 use rand::seq::SliceRandom;
+use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
-use std::time::{Instant, SystemTime};
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
 
 use serde_derive::{Deserialize, Serialize};
 
 use rspotify::spotify::client::Spotify;
 use rspotify::spotify::model::device::Device;
 use rspotify::spotify::oauth2::{SpotifyClientCredentials, TokenInfo};
+use rspotify::spotify::senum::Country;
 
-use crate::commands::TaskQueue;
+use crate::cache::Cache;
+use crate::calendar::Calendars;
+use crate::commands::{LockedTaskQueue, TaskQueue};
 use crate::common::*;
+use crate::config::{
+    Config, ExternalPolicy, ListOrder, PlaybackConfig, QuietAction, VolumeProfile,
+};
+use crate::metrics;
+use crate::spotify_ext;
 
 /// Handles the requested song queue, with weighting etc
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TheList {
-    pub songs: std::collections::HashMap<String, BasicSongInfo>,
+    pub songs: std::collections::HashMap<String, ListEntry>,
     pub version: u64,
 }
 
+/// Songs which should not be picked next, as long as there are others to choose from
+#[derive(Debug, Default)]
+struct Avoid {
+    /// Spotify IDs of artists played recently
+    artists: Vec<String>,
+    /// Requester of the last song
+    requester: Option<String>,
+}
+
+/// Narrow down candidates to those passing `keep`, unless none do
+fn narrow<F>(candidates: &mut Vec<&ListEntry>, keep: F)
+where
+    F: Fn(&ListEntry) -> bool,
+{
+    let kept: Vec<&ListEntry> = candidates.iter().cloned().filter(|e| keep(e)).collect();
+    if !kept.is_empty() {
+        *candidates = kept;
+    }
+}
+
+/// Candidate which has waited longest
+fn oldest<'a>(candidates: &[&'a ListEntry]) -> Option<&'a ListEntry> {
+    candidates.iter().cloned().min_by_key(|e| e.requested_at_ms)
+}
+
+/// Jump in audio features assumed when either song has not been analysed
+const UNKNOWN_DISTANCE: f32 = 1.0;
+
+/// Candidate closest in energy and tempo to `previous`, the longest waiting if several are equal
+fn smoothest<'a>(
+    candidates: &[&'a ListEntry],
+    previous: Option<&AudioInfo>,
+) -> Option<&'a ListEntry> {
+    let distance = |e: &ListEntry| match (previous, &e.features) {
+        (Some(p), Some(f)) => p.distance(f),
+        _ => UNKNOWN_DISTANCE,
+    };
+    candidates.iter().cloned().min_by(|a, b| {
+        distance(a)
+            .partial_cmp(&distance(b))
+            .unwrap_or(Ordering::Equal)
+            .then(a.requested_at_ms.cmp(&b.requested_at_ms))
+    })
+}
+
 impl TheList {
     pub fn new() -> TheList {
         TheList {
@@ -29,31 +85,87 @@ impl TheList {
         }
     }
 
-    fn add(&mut self, track_id: BasicSongInfo) {
-        debug!("Added song {:?}", track_id);
-        let key = &track_id.spotify_uri;
-        self.songs.entry(key.clone()).or_insert(track_id);
+    fn add(&mut self, entry: ListEntry) {
+        debug!("Added song {:?}", entry);
+        let key = &entry.song.spotify_uri;
+        self.songs.entry(key.clone()).or_insert(entry);
         trace!("The list after: {:?}", self);
         self.version += 1;
     }
 
-    fn nextup(&mut self) -> Option<BasicSongInfo> {
+    /// Take the next song to play from the list, in the order set by `cfg`. Songs matching
+    /// `avoid` are only picked if there is nothing else. `previous` is the last song's audio
+    /// features, for smooth ordering
+    fn nextup(
+        &mut self,
+        cfg: &PlaybackConfig,
+        avoid: &Avoid,
+        previous: Option<&AudioInfo>,
+    ) -> Option<ListEntry> {
         let chosen_key = {
-            let mut rng = rand::thread_rng();
-            let keys: Vec<&String> = self.songs.keys().collect();
-            let hm = keys.choose(&mut rng)?;
-            hm.clone()
-        }
-        .clone();
+            let mut candidates: Vec<&ListEntry> = self.songs.values().collect();
+            narrow(&mut candidates, |e| {
+                !e.song.artists.iter().any(|a| avoid.artists.contains(&a.id))
+            });
+            if avoid.requester.is_some() {
+                narrow(&mut candidates, |e| e.requested_by != avoid.requester);
+            }
+            let chosen = match cfg.order {
+                ListOrder::Random => *candidates.choose(&mut rand::thread_rng())?,
+                ListOrder::Smooth => {
+                    // Anything waiting too long goes first, so every request gets played
+                    let max_wait_ms = u64::from(cfg.max_wait_minutes) * 60 * 1000;
+                    let cutoff_ms = unix_time_ms(SystemTime::now()).saturating_sub(max_wait_ms);
+                    let overdue: Vec<&ListEntry> = candidates
+                        .iter()
+                        .cloned()
+                        .filter(|e| e.requested_at_ms < cutoff_ms)
+                        .collect();
+                    match oldest(&overdue) {
+                        Some(e) => e,
+                        None => smoothest(&candidates, previous)?,
+                    }
+                }
+            };
+            chosen.song.spotify_uri.clone()
+        };
 
         if let Entry::Occupied(o) = self.songs.entry(chosen_key) {
             let (_, value) = o.remove_entry();
             self.version += 1;
-            return Some(value);
+            Some(value)
         } else {
-            return None;
+            None
         }
     }
+
+    /// Take song with the given URI out of the list, if it is there
+    fn take(&mut self, uri: &str) -> Option<ListEntry> {
+        let entry = self.songs.remove(uri)?;
+        self.version += 1;
+        Some(entry)
+    }
+
+    /// Song in the list which is the same as the given one, see `BasicSongInfo::is_same_song`
+    fn find_same(&self, song: &BasicSongInfo) -> Option<&BasicSongInfo> {
+        self.songs
+            .values()
+            .map(|e| &e.song)
+            .find(|s| s.is_same_song(song))
+    }
+
+    /// Read list previously written with `save`
+    pub fn load(path: &std::path::Path) -> ClientResult<TheList> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    /// Write list to file as JSON
+    pub fn save(&self, path: &std::path::Path) -> ClientResult<()> {
+        let f = std::fs::File::create(path)?;
+        serde_json::to_writer(f, self)?;
+        Ok(())
+    }
 }
 
 impl Drop for TheList {
@@ -62,31 +174,218 @@ impl Drop for TheList {
     }
 }
 
+/// Device to select automatically, matched by ID, or by name and type if its ID changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreferredDevice {
+    pub id: String,
+    pub name: String,
+    pub device_type: String,
+}
+
+impl PreferredDevice {
+    fn from_device(d: &Device) -> PreferredDevice {
+        PreferredDevice {
+            id: d.id.clone(),
+            name: d.name.clone(),
+            device_type: format!("{:?}", d._type),
+        }
+    }
+
+    /// Find this device in a device listing
+    fn find(&self, devices: &[Device]) -> Option<Device> {
+        devices
+            .iter()
+            .find(|d| d.id == self.id)
+            .or_else(|| {
+                devices
+                    .iter()
+                    .find(|d| d.name == self.name && format!("{:?}", d._type) == self.device_type)
+            })
+            .cloned()
+    }
+
+    /// Read device previously written with `save`
+    pub fn load(path: &std::path::Path) -> ClientResult<Option<PreferredDevice>> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    /// Write device (or lack of one) to file as JSON
+    pub fn save(device: &Option<PreferredDevice>, path: &std::path::Path) -> ClientResult<()> {
+        let f = std::fs::File::create(path)?;
+        serde_json::to_writer(f, device)?;
+        Ok(())
+    }
+}
+
+/// Quiet period currently in effect, and how to undo it afterwards
+#[derive(Debug, Clone)]
+struct ActiveQuiet {
+    period: QuietPeriod,
+    /// Playback was paused when it started
+    paused: bool,
+    /// Volume to go back to afterwards
+    restore_volume: Option<u8>,
+}
+
+/// Time allowed for Spotify to reflect a playback command before checking status again
+const SETTLE_MS: u32 = 1000;
+
+/// Song handed to Spotify's own play queue, before the current song has finished
+#[derive(Debug, Clone)]
+struct Prequeued {
+    /// Song which should play next
+    entry: ListEntry,
+    /// URI of the song playing when it was queued
+    after_uri: String,
+    /// Picked by radio mode, so is not returned to the list if forgotten
+    auto_picked: bool,
+}
+
+/// Song left in Spotify's own queue after Jukeula stopped expecting it, e.g because it was
+/// started directly instead. Spotify has no way to remove it, so it is dealt with once it starts
+#[derive(Debug, Clone, PartialEq)]
+struct StaleQueued {
+    uri: String,
+    /// Also started directly, so the first time it starts is expected
+    started_directly: bool,
+}
+
+/// Most songs remembered in `Client::stale_queued`, as Spotify may drop its queue without them
+/// ever playing, e.g if the device goes away
+const MAX_STALE_QUEUED: usize = 10;
+
+/// Playing position must go back by more than this to count as the song starting again
+const RESTART_MARGIN_MS: u32 = 5000;
+
+/// What to do when a song in `Client::stale_queued` starts playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StaleAction {
+    /// Jukeula started it directly, the copy in Spotify's queue is still to come
+    Expect,
+    /// Still waiting in the list, so take it from there and let it play now
+    Adopt,
+    /// Already played, so skip to the next track
+    Skip,
+}
+
+/// Decide what to do about `stale`, given the song and position playing at the previous and
+/// current status checks. None if it has not just started
+fn reconcile_stale(
+    stale: &StaleQueued,
+    previous: Option<(&str, u32)>,
+    current: Option<(&str, u32)>,
+    in_list: bool,
+) -> Option<StaleAction> {
+    let (uri, progress_ms) = current?;
+    if uri != stale.uri {
+        return None;
+    }
+    let just_started = match previous {
+        Some((previous_uri, previous_ms)) => {
+            previous_uri != uri || progress_ms + RESTART_MARGIN_MS < previous_ms
+        }
+        None => true,
+    };
+    if !just_started {
+        None
+    } else if stale.started_directly {
+        Some(StaleAction::Expect)
+    } else if in_list {
+        Some(StaleAction::Adopt)
+    } else {
+        Some(StaleAction::Skip)
+    }
+}
+
 /// Handles playback/queue logic and commands Spotify
 pub struct Client {
     spotify: Option<Spotify>,
     device: Option<Device>,
     pub the_list: TheList,
     last_status_check: Option<SystemTime>,
+    /// Delay between `last_status_check` and the next one, see `poll_delay_ms`
+    next_status_check_ms: u32,
+    /// When playback last stopped, for backing off polling when idle
+    idle_since: Option<Instant>,
+    /// When `update_player_status` last succeeded
+    pub last_status_update: Option<SystemTime>,
     last_token_refresh: Option<SystemTime>,
     pub status: PlaybackStatus,
-    status_check_interval_ms: u32,
-    token_refresh_interval_ms: u32,
-    skip_votes: u32,
+    pub cfg: Config,
+    /// Client IDs which voted to skip `skip_votes_uri`, so each only counts once
+    skip_votes: HashSet<String>,
+    skip_votes_uri: Option<String>,
+    /// Number of times rebuilt after a panic, see `rebuild`
+    restarts: u32,
+    /// Song queued in Spotify in `gapless` mode, until it is seen playing
+    prequeued: Option<Prequeued>,
+    /// Songs still in Spotify's queue which should not play from there, oldest first
+    stale_queued: VecDeque<StaleQueued>,
+    /// URI of the song Jukeula most recently started, anything else playing is `External`
+    last_started_uri: Option<String>,
+    /// Track seen playing when external playback was first noticed, for `ExternalPolicy::AfterTrack`
+    external_uri: Option<String>,
+    /// Song to pick up from where it left off, see `resume_interrupted`
+    interrupted: Option<InterruptedSong>,
+    /// Last volume profile applied, so it is only applied once and manual changes are kept
+    volume_profile: Option<VolumeProfile>,
+    /// Admin has bypassed the schedule until this time
+    schedule_override_until: Option<SystemTime>,
+    /// Paused something which started outside office hours, so the list takes over once they begin
+    schedule_paused: bool,
+    /// Events from `quiet.calendars`
+    calendars: Calendars,
+    quiet: Option<ActiveQuiet>,
+    /// Device chosen by the user, selected again automatically whenever it is missing
+    preferred_device: Option<PreferredDevice>,
+    /// Recently started songs, oldest first
+    pub history: VecDeque<PlayedSong>,
+    /// Country of the user's account once looked up, see `market`
+    account_market: Option<Option<String>>,
+    /// Search and browse results, by query
+    search_cache: Cache<String, SearchResult>,
+    /// Track details by URI
+    track_cache: Cache<String, BasicSongInfo>,
+    /// Radio mode is on, initially from `radio.enabled`
+    radio_enabled: bool,
+    /// URI of the song most recently started by radio mode, for `PlaybackStatus::auto_picked`
+    auto_picked_uri: Option<String>,
+    /// Radio mode found nothing to play, so waits until then before trying again
+    radio_retry_at: Option<Instant>,
+    /// Client and time (milliseconds since Unix epoch) of songs added within the last hour,
+    /// oldest first, for `quota.max_per_hour`
+    recent_requests: VecDeque<(String, u64)>,
+}
+
+/// How long to wait before asking for recommendations again, after finding nothing suitable
+const RADIO_RETRY_MS: u64 = 60_000;
+
+/// Add Spotify field filter to search query, e.g `artist:"Name"`
+fn add_search_filter(query: &mut String, field: &str, value: &Option<String>) {
+    if let Some(v) = value {
+        if !query.is_empty() {
+            query.push(' ');
+        }
+        query.push_str(&format!("{}:\"{}\"", field, v.replace('"', "")));
+    }
 }
 
-/// Convert `Duration` into milliseconds (as u64), to be used until
-/// the `as_millis` method is stable (returns u128). Max `u64` milliseconds
-/// is only 49 days whereas `u128` is only 10^28 years..
-/// Enough for our purposes
-fn duration_as_millis(d: std::time::Duration) -> u64 {
-    // TOOD: Replace when Duration::as_millis becomes stable
-    (d.as_secs() * 1000) + u64::from(d.subsec_millis())
+/// Leave out tracks hidden by search or browse options
+fn filter_tracks<I>(tracks: I, playable_only: bool, hide_explicit: bool) -> Vec<BasicSongInfo>
+where
+    I: IntoIterator<Item = BasicSongInfo>,
+{
+    tracks
+        .into_iter()
+        .filter(|s| !(playable_only && s.is_playable == Some(false)))
+        .filter(|s| !(hide_explicit && s.explicit))
+        .collect()
 }
 
 /// Turn Spotify API structure into internal `PlaybackStatus`
 fn parse_playing_context(
-    ctx: Option<rspotify::spotify::model::context::SimplifiedPlayingContext>,
+    ctx: Option<rspotify::spotify::model::context::FullPlayingContext>,
 ) -> PlaybackStatus {
     if let Some(c) = ctx {
         let current_state = if c.is_playing {
@@ -103,37 +402,112 @@ fn parse_playing_context(
             }
         };
 
-        let song = c.item.and_then(|t| Some(BasicSongInfo::from(t)));
+        let song = c.item.map(BasicSongInfo::from);
         PlaybackStatus {
             state: current_state,
-            song: song,
+            song,
             progress_ms: c.progress_ms,
+            spotify_timestamp: Some(c.timestamp),
+            volume_percent: Some(c.device.volume_percent),
+            ..PlaybackStatus::default()
         }
     } else {
         PlaybackStatus::default()
     }
 }
 
+/// Read preferred device from `device_file`, if configured
+fn load_preferred_device(cfg: &Config) -> Option<PreferredDevice> {
+    let path = cfg.device_file.as_ref().filter(|p| p.exists())?;
+    match PreferredDevice::load(path) {
+        Ok(d) => {
+            if let Some(d) = &d {
+                info!("Preferred device is {:?} ({})", d.name, d.device_type);
+            }
+            d
+        }
+        Err(e) => {
+            warn!("Could not load device from {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
 impl Client {
-    pub fn new() -> Client {
+    pub fn new(cfg: &Config) -> Client {
         Client {
             spotify: None,
             device: None,
             the_list: TheList::new(),
             last_status_check: None,
+            next_status_check_ms: 0,
+            idle_since: None,
+            last_status_update: None,
             last_token_refresh: None,
             status: PlaybackStatus::default(),
-            status_check_interval_ms: 1000,
-            token_refresh_interval_ms: 1000 * 60 * 5,
-            skip_votes: 0,
+            cfg: cfg.clone(),
+            skip_votes: HashSet::new(),
+            skip_votes_uri: None,
+            restarts: 0,
+            prequeued: None,
+            stale_queued: VecDeque::new(),
+            last_started_uri: None,
+            external_uri: None,
+            interrupted: None,
+            volume_profile: None,
+            schedule_override_until: None,
+            schedule_paused: false,
+            calendars: Calendars::default(),
+            quiet: None,
+            preferred_device: load_preferred_device(cfg),
+            history: VecDeque::new(),
+            account_market: None,
+            search_cache: Cache::new("search", cfg.cache.search_entries, cfg.cache.ttl_ms),
+            track_cache: Cache::new("track", cfg.cache.track_entries, cfg.cache.ttl_ms),
+            radio_enabled: cfg.radio.enabled,
+            auto_picked_uri: None,
+            radio_retry_at: None,
+            recent_requests: VecDeque::new(),
+        }
+    }
+
+    /// Apply newly loaded config, logging what changed. Settings only read at startup are left as-is
+    pub fn reload_config(&mut self, new: &Config) {
+        let changes = self.cfg.changes(new);
+        if changes.is_empty() {
+            info!("Config reloaded, no changes");
+            return;
+        }
+        for c in changes {
+            if c.needs_restart {
+                warn!(
+                    "Config setting {} changed, but requires a restart to apply",
+                    c.key
+                );
+            } else {
+                info!("Config setting {} changed", c.key);
+            }
         }
+        if new.radio.enabled != self.cfg.radio.enabled {
+            // Replaces any admin override
+            self.radio_enabled = new.radio.enabled;
+        }
+        self.cfg = self.cfg.reloaded(new);
+        let cache = &self.cfg.cache;
+        self.search_cache
+            .configure(cache.search_entries, cache.ttl_ms);
+        self.track_cache
+            .configure(cache.track_entries, cache.ttl_ms);
     }
 
     /// End session with Spotify
     pub fn clear_auth(&mut self) {
+        self.forget_prequeued();
         self.spotify = None;
         self.device = None;
+        self.account_market = None;
         self.status = PlaybackStatus::default();
+        self.poll_now();
     }
 
     pub fn set_auth_token(&mut self, token: &TokenInfo) {
@@ -146,19 +520,69 @@ impl Client {
                 .client_credentials_manager(client_credential)
                 .build(),
         );
+        self.poll_now();
+    }
+
+    /// Current auth token, if authenticated
+    pub fn auth_token(&self) -> Option<TokenInfo> {
+        self.spotify
+            .as_ref()
+            .and_then(|s| s.client_credentials_manager.as_ref())
+            .and_then(|ccm| ccm.token_info.clone())
+    }
+
+    /// Create a fresh client with the same config, auth token and device, but the given list.
+    /// Used to recover after a panic, when `self` may be in an inconsistent state
+    pub fn rebuild(&self, mut the_list: TheList) -> Client {
+        let mut c = Client::new(&self.cfg);
+        if let Some(t) = self.auth_token() {
+            c.set_auth_token(&t);
+        }
+        c.device = self.device.clone();
+        c.skip_votes = self.skip_votes.clone();
+        c.skip_votes_uri = self.skip_votes_uri.clone();
+        c.restarts = self.restarts + 1;
+        c.status.restarts = c.restarts;
+        // Still in Spotify's queue, so should be seen starting. The list may be from before it
+        // was taken out
+        if let Some(p) = &self.prequeued {
+            the_list.take(&p.entry.song.spotify_uri);
+        }
+        c.prequeued = self.prequeued.clone();
+        c.stale_queued = self.stale_queued.clone();
+        c.preferred_device = self.preferred_device.clone();
+        c.last_started_uri = self.last_started_uri.clone();
+        c.interrupted = self.interrupted.clone();
+        c.schedule_override_until = self.schedule_override_until;
+        c.schedule_paused = self.schedule_paused;
+        c.quiet = self.quiet.clone();
+        c.history = self.history.clone();
+        c.account_market = self.account_market.clone();
+        c.search_cache = self.search_cache.clone();
+        c.track_cache = self.track_cache.clone();
+        c.radio_enabled = self.radio_enabled;
+        c.auto_picked_uri = self.auto_picked_uri.clone();
+        c.recent_requests = self.recent_requests.clone();
+        c.the_list = the_list;
+        c
     }
 
     /// Refresh auth token which expires every hour or so
     fn refresh_auth_token(&mut self) -> ClientResult<()> {
         let c = self.get_spotify()?;
         let oauth = rspotify::spotify::oauth2::SpotifyOAuth::default()
-            .scope("user-read-playback-state user-modify-playback-state")
+            .scope(&self.cfg.spotify.scope_string())
             .build();
 
         if let Some(ref ccm) = c.client_credentials_manager {
-            let t = ccm.token_info.clone().unwrap();
-            let rt = t.refresh_token.clone().unwrap();
-            let newtoken = oauth.refresh_access_token(&rt).unwrap();
+            let rt = ccm
+                .token_info
+                .as_ref()
+                .and_then(|t| t.refresh_token.clone())
+                .ok_or_else(|| format_err!("No refresh token to refresh auth with"))?;
+            let newtoken =
+                metrics::time_api("refresh_token", || Ok(oauth.refresh_access_token(&rt)))?
+                    .ok_or_else(|| format_err!("Spotify did not refresh the auth token"))?;
             self.set_auth_token(&newtoken);
         }
         Ok(())
@@ -167,7 +591,7 @@ impl Client {
     fn get_spotify(&self) -> ClientResult<&Spotify> {
         match &self.spotify {
             None => Err(format_err!("Client not authenticated")),
-            Some(c) => Ok(&c),
+            Some(c) => Ok(c),
         }
     }
 
@@ -178,7 +602,8 @@ impl Client {
         queue: &mut TaskQueue,
     ) -> ClientResult<()> {
         trace!("Listing devices");
-        let devices = self.get_spotify()?.device()?;
+        let c = self.get_spotify()?;
+        let devices = metrics::time_api("device", || c.device())?;
         queue.respond(CommandResponse {
             tid: params.tid,
             value: CommandResponseDataType::DeviceList(DeviceListResult {
@@ -191,11 +616,14 @@ impl Client {
     /// Sets one of the devices from `list_devices` as the active one
     pub fn set_active_device(&mut self, id: String) -> ClientResult<()> {
         trace!("Setting {} as active device", id);
-        let devices = self.get_spotify()?.device()?;
+        let c = self.get_spotify()?;
+        let devices = metrics::time_api("device", || c.device())?;
         for d in devices.devices {
             if d.id == id {
                 info!("Device set as active: {:?}", d);
+                self.set_preferred_device(Some(PreferredDevice::from_device(&d)));
                 self.device = Some(d);
+                self.poll_now();
                 return Ok(());
             }
         }
@@ -203,145 +631,1590 @@ impl Client {
     }
 
     pub fn clear_device(&mut self) {
+        self.forget_prequeued();
         self.device = None;
+        self.set_preferred_device(None);
+        self.poll_now();
+    }
+
+    /// Remember device to reselect automatically, saving it to `device_file`
+    fn set_preferred_device(&mut self, device: Option<PreferredDevice>) {
+        self.preferred_device = device;
+        if let Some(path) = &self.cfg.device_file {
+            if let Err(e) = PreferredDevice::save(&self.preferred_device, path) {
+                warn!("Could not save device to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Select the preferred device if it is available again, moving playback to it
+    fn reselect_device(&mut self) -> ClientResult<()> {
+        let preferred = match &self.preferred_device {
+            Some(p) if self.device.is_none() && self.spotify.is_some() => p.clone(),
+            _ => return Ok(()),
+        };
+        let c = self.get_spotify()?;
+        let devices = metrics::time_api("device", || c.device())?;
+        let d = match preferred.find(&devices.devices) {
+            Some(d) => d,
+            None => {
+                trace!("Preferred device {:?} not available", preferred.name);
+                return Ok(());
+            }
+        };
+
+        info!("Preferred device available again, selecting {:?}", d);
+        let r = metrics::time_api("transfer_playback", || {
+            spotify_ext::transfer_playback(c, &d.id, false)
+        });
+        if let Err(e) = r {
+            // Playback commands name the device anyway, so this is not fatal
+            warn!("Could not transfer playback to {:?}: {}", d.name, e);
+        }
+        if d.id != preferred.id {
+            // Same device, new ID
+            self.set_preferred_device(Some(PreferredDevice::from_device(&d)));
+        }
+        self.device = Some(d);
+        self.poll_now();
+        Ok(())
+    }
+
+    /// After an error, check the active device still exists, so it can be reselected once it returns
+    fn check_device_present(&mut self) {
+        let id = match &self.device {
+            Some(d) => d.id.clone(),
+            None => return,
+        };
+        let devices = match self
+            .get_spotify()
+            .and_then(|c| metrics::time_api("device", || c.device()))
+        {
+            Ok(d) => d.devices,
+            // Can't tell, e.g network trouble
+            Err(_) => return,
+        };
+        if !devices.iter().any(|d| d.id == id) {
+            warn!("Active device has gone away, waiting for it to return");
+            self.forget_prequeued();
+            self.device = None;
+            self.poll_now();
+        }
     }
 
     /// Pause playback
-    pub fn pause(&self) -> ClientResult<()> {
+    pub fn pause(&mut self) -> ClientResult<()> {
         info!("Pausing");
-        let id = self.device.clone().and_then(|x| Some(x.id));
-        self.get_spotify()?.pause_playback(id)?;
+        let id = self.device.clone().map(|x| x.id);
+        let c = self.get_spotify()?;
+        metrics::time_api("pause_playback", || c.pause_playback(id))?;
+        self.poll_after_settle();
+        Ok(())
+    }
+
+    /// Pause, remembering the current song so it can be resumed even if something else is
+    /// played in the meantime
+    pub fn interrupt(&mut self) -> ClientResult<()> {
+        if let Some(i) = self.our_song_progress() {
+            info!("Interrupting {:?} at {}ms", i.song.title, i.progress_ms);
+            self.interrupted = Some(i);
+        }
+        self.pause()
+    }
+
+    /// Restart the interrupted song where it left off, or just click play if nothing was interrupted
+    pub fn resume_interrupted(&mut self) -> ClientResult<()> {
+        let i = match self.interrupted.take() {
+            Some(i) => i,
+            None => return self.resume(),
+        };
+        info!("Resuming {:?} at {}ms", i.song.title, i.progress_ms);
+        let id = self.device.clone().map(|x| x.id);
+        let c = self.get_spotify()?;
+        let r = metrics::time_api("start_playback", || {
+            spotify_ext::start_playback_at(c, &i.song.spotify_uri, i.progress_ms, id)
+        });
+        if let Err(e) = r {
+            // Keep it for next time
+            self.interrupted = Some(i);
+            return Err(e);
+        }
+        self.last_started_uri = Some(i.song.spotify_uri);
+        self.status.state = PlaybackState::EnqueuedAndWaiting;
+        self.poll_after_settle();
         Ok(())
     }
 
+    /// Song Jukeula started, if playing or paused, with its estimated current progress
+    fn our_song_progress(&self) -> Option<InterruptedSong> {
+        let s = &self.status;
+        if !(s.state == PlaybackState::Playing || s.state == PlaybackState::Paused)
+            || !self.is_ours(s)
+        {
+            return None;
+        }
+        let song = s.song.clone()?;
+        let mut progress_ms = s.progress_ms?;
+        if let (PlaybackState::Playing, Some(sampled)) = (s.state, s.sampled_at_ms) {
+            let elapsed = unix_time_ms(SystemTime::now()).saturating_sub(sampled);
+            progress_ms = progress_ms.saturating_add(elapsed as u32);
+        }
+        if progress_ms >= song.duration_ms {
+            return None;
+        }
+        Some(InterruptedSong { song, progress_ms })
+    }
+
     /// Clicks the play button
-    pub fn resume(&self) -> ClientResult<()> {
+    pub fn resume(&mut self) -> ClientResult<()> {
         info!("Resume");
-        let id = self.device.clone().and_then(|x| Some(x.id));
-        self.get_spotify()?.start_playback(id, None, None, None)?;
+        let id = self.device.clone().map(|x| x.id);
+        let c = self.get_spotify()?;
+        metrics::time_api("start_playback", || c.start_playback(id, None, None, None))?;
+        self.poll_after_settle();
         Ok(())
     }
 
-    pub fn search(&self, params: &SearchParams, queue: &mut TaskQueue) -> ClientResult<()> {
-        debug!("Searching for {:?}", params);
-        let start = Instant::now();
-        let search = self
-            .get_spotify()?
-            .search_track(&params.title, 40, 0, None)?;
-        let dur = start.elapsed();
-        trace!("Search took {}ms", duration_as_millis(dur));
-        let mut sr = vec![];
-        for s in search.tracks.items {
-            sr.push(s.into());
+    /// Current state of the office-hours schedule, clearing any expired override
+    fn schedule_state(&mut self) -> ScheduleState {
+        if self.cfg.schedule.hours.is_empty() {
+            return ScheduleState::Unrestricted;
+        }
+        if let Some(until) = self.schedule_override_until {
+            if SystemTime::now() < until {
+                return ScheduleState::Overridden;
+            }
+            info!("Schedule override ended");
+            self.schedule_override_until = None;
+        }
+        let now = self.cfg.local_now();
+        if self.cfg.schedule.is_open(now.weekday(), now.time()) {
+            ScheduleState::Open
+        } else {
+            ScheduleState::Closed
+        }
+    }
+
+    /// If the schedule still allows playing in given number of milliseconds, e.g when a song
+    /// queued now would start
+    fn schedule_open_in(&self, ms: u32) -> bool {
+        if self.cfg.schedule.hours.is_empty() {
+            return true;
+        }
+        let later = std::time::Duration::from_millis(u64::from(ms));
+        if let Some(until) = self.schedule_override_until {
+            if SystemTime::now() + later < until {
+                return true;
+            }
+        }
+        let t = self.cfg.local_now() + chrono::Duration::milliseconds(i64::from(ms));
+        self.cfg.schedule.is_open(t.weekday(), t.time())
+    }
+
+    /// Outside office hours, pause anything which has started since the previous status check,
+    /// whether queued in Spotify or played by someone else. The song playing when they ended is
+    /// left to finish
+    fn stop_after_hours(&mut self, previous: Option<(String, u32)>) -> ClientResult<()> {
+        let state = self.status.state;
+        if state != PlaybackState::Playing && state != PlaybackState::External {
+            return Ok(());
+        }
+        let current_uri = self.status.song.as_ref().map(|s| &s.spotify_uri);
+        let started = match &previous {
+            Some((uri, _)) => Some(uri) != current_uri,
+            None => true,
+        };
+        if started {
+            info!("{:?} started outside office hours, pausing", current_uri);
+            self.schedule_paused = true;
+            self.pause()?;
         }
-        queue.respond(CommandResponse {
-            tid: params.tid,
-            value: CommandResponseDataType::Search(SearchResult { items: sr }),
-        });
         Ok(())
     }
 
-    /// Update `status` field
-    pub fn update_player_status(&mut self) -> ClientResult<()> {
-        self.status = if let None = self.spotify {
-            // No spotify API client
-            PlaybackStatus {
-                state: PlaybackState::NoAuth,
-                ..PlaybackStatus::default()
+    /// Copy schedule state into `status`
+    fn update_schedule_status(&mut self) {
+        self.status.schedule = self.schedule_state();
+        self.status.schedule_override_until_ms = self.schedule_override_until.map(unix_time_ms);
+    }
+
+    /// Ignore the schedule for given number of minutes, or return to following it if zero
+    pub fn override_schedule(&mut self, minutes: u32) {
+        if minutes == 0 {
+            info!("Schedule override cancelled");
+            self.schedule_override_until = None;
+        } else {
+            info!("Ignoring schedule for {} minutes", minutes);
+            self.schedule_override_until =
+                Some(SystemTime::now() + std::time::Duration::from_secs(u64::from(minutes) * 60));
+        }
+        self.update_schedule_status();
+        self.poll_now();
+    }
+
+    /// Check calendars for quiet periods, pausing or turning down the volume when one starts and
+    /// undoing it when it ends
+    fn update_quiet_period(&mut self) -> ClientResult<()> {
+        let q = self.cfg.quiet.clone();
+        self.calendars
+            .refresh(&q.calendars, q.refresh_ms, self.cfg.zone());
+        let current = self
+            .calendars
+            .active(Utc::now(), |e| q.matches(&e.summary))
+            .map(|(e, end)| QuietPeriod {
+                summary: e.summary.clone(),
+                ends_at_ms: end.timestamp_millis() as u64,
+            });
+
+        match (self.quiet.take(), current) {
+            (None, Some(period)) => {
+                info!("Quiet period started: {:?}", period.summary);
+                let mut active = ActiveQuiet {
+                    period,
+                    paused: false,
+                    restore_volume: None,
+                };
+                // Recorded even if this fails, rather than retrying and failing every check
+                match q.action {
+                    QuietAction::Pause => {
+                        let s = self.status.state;
+                        if s == PlaybackState::Playing || s == PlaybackState::External {
+                            match self.interrupt() {
+                                Ok(()) => active.paused = true,
+                                Err(e) => warn!("Could not pause for quiet period: {}", e),
+                            }
+                        }
+                    }
+                    QuietAction::Volume => {
+                        active.restore_volume = self.status.volume_percent.map(|v| v as u8);
+                        if let Err(e) = self.set_volume(q.volume_percent) {
+                            warn!("Could not turn volume down for quiet period: {}", e);
+                        }
+                    }
+                }
+                self.quiet = Some(active);
             }
-        } else if let None = self.device {
-            // No active device
-            PlaybackStatus {
-                state: PlaybackState::NoDevice,
-                ..PlaybackStatus::default()
+            (Some(active), None) => {
+                info!("Quiet period ended: {:?}", active.period.summary);
+                // Kept until undone successfully, so it is retried next time if this fails
+                self.quiet = Some(active.clone());
+                if active.paused {
+                    self.resume_interrupted()?;
+                }
+                if let Some(v) = active.restore_volume {
+                    self.set_volume(v)?;
+                }
+                self.quiet = None;
             }
-        } else {
-            // Check what is playing
-            trace!("Querying current playing");
-            let x = self.get_spotify()?.current_playing(None)?;
-            parse_playing_context(x)
-        };
+            (Some(mut active), Some(period)) => {
+                // Event may have been edited
+                active.period = period;
+                self.quiet = Some(active);
+            }
+            (None, None) => (),
+        }
+        self.status.quiet_period = self.quiet.as_ref().map(|a| a.period.clone());
         Ok(())
     }
 
-    /// Adds specified track to "the list for consideration"
-    pub fn request(&mut self, track_id: String) -> ClientResult<()> {
-        debug!("Requested song {}", track_id);
+    /// If songs from the list may be started, according to the schedule and any quiet period
+    fn playback_allowed(&mut self) -> bool {
+        let quiet_pause = self.quiet.is_some() && self.cfg.quiet.action == QuietAction::Pause;
+        self.schedule_state() != ScheduleState::Closed && !quiet_pause
+    }
+
+    /// Jump to position in the current song
+    pub fn seek(&mut self, position_ms: u32) -> ClientResult<()> {
+        if let Some(s) = &self.status.song {
+            if position_ms >= s.duration_ms {
+                return Err(format_err!(
+                    "Cannot seek to {}ms, song is only {}ms long",
+                    position_ms,
+                    s.duration_ms
+                ));
+            }
+        }
+        info!("Seeking to {}ms", position_ms);
+        let id = self.device.clone().map(|x| x.id);
         let c = self.get_spotify()?;
-        let track = c.track(&track_id)?;
-        let x: BasicSongInfo = track.into();
-        if x.title.to_lowercase().contains("scatman")
-            || x.title.to_lowercase().contains("freestyler")
-        {
+        metrics::time_api("seek_track", || c.seek_track(position_ms, id))?;
+
+        // Show new position straight away, rather than waiting for next status check
+        self.status.progress_ms = Some(position_ms);
+        self.status.sampled_at_ms = Some(unix_time_ms(SystemTime::now()));
+        self.poll_after_settle();
+        Ok(())
+    }
+
+    /// Set device volume, 0 to 100
+    pub fn set_volume(&mut self, percent: u8) -> ClientResult<()> {
+        if percent > 100 {
+            return Err(format_err!("Volume must be 0 to 100, got {}", percent));
+        }
+        info!("Setting volume to {}%", percent);
+        let id = self.device.clone().map(|x| x.id);
+        let c = self.get_spotify()?;
+        metrics::time_api("volume", || c.volume(percent, id))?;
+        self.status.volume_percent = Some(percent.into());
+        self.poll_after_settle();
+        Ok(())
+    }
+
+    /// Set volume when a new volume profile starts
+    fn apply_volume_profile(&mut self) -> ClientResult<()> {
+        if self.spotify.is_none() || self.device.is_none() {
+            return Ok(());
+        }
+        if self.quiet.is_some() && self.cfg.quiet.action == QuietAction::Volume {
+            // Applied once the quiet period ends
+            return Ok(());
+        }
+        let now = self.cfg.local_now().time();
+        let active = self.cfg.volume.active_profile(now).cloned();
+        if active == self.volume_profile {
             return Ok(());
         }
-        self.the_list.add(x);
+        match (&active, self.cfg.volume.default_percent) {
+            (Some(p), _) => {
+                info!("Volume profile {}-{} started", p.from, p.to);
+                self.set_volume(p.percent)?;
+            }
+            (None, Some(percent)) if self.volume_profile.is_some() => {
+                info!("Volume profile ended, restoring default volume");
+                self.set_volume(percent)?;
+            }
+            (None, _) => (),
+        }
+        self.volume_profile = active;
         Ok(())
     }
 
-    /// Make a song start playing, replacing anything currently playing
-    pub fn load_song(&mut self, track: BasicSongInfo) -> ClientResult<()> {
-        trace!("Starting playback of song");
-        let id = self.device.clone().and_then(|x| Some(x.id));
-        self.get_spotify()?
-            .start_playback(id, None, Some(vec![track.spotify_uri]), None)?;
+    /// Market to search in, from config or else the user's account
+    fn market(&mut self) -> Option<Country> {
+        if let Some(m) = &self.cfg.spotify.market {
+            return Country::from_str(m);
+        }
+        if self.account_market.is_none() {
+            let c = self.get_spotify().ok()?;
+            let country = match metrics::time_api("me", || spotify_ext::user_country(c)) {
+                Ok(country) => country,
+                Err(e) => {
+                    warn!("Could not look up account country: {}", e);
+                    return None;
+                }
+            };
+            if country.is_none() {
+                info!("Account country not available (needs the user-read-private scope), searching without a market");
+            }
+            self.account_market = Some(country);
+        }
+        match &self.account_market {
+            Some(Some(country)) => Country::from_str(country),
+            _ => None,
+        }
+    }
+
+    pub fn search(&mut self, params: &SearchParams, queue: &mut TaskQueue) -> ClientResult<()> {
+        let value = match self.search_page(params) {
+            Ok(r) => CommandResponseDataType::Search(r),
+            Err(e) => CommandResponseDataType::Error(e.to_string()),
+        };
+        queue.respond(CommandResponse {
+            tid: params.tid,
+            value,
+        });
         Ok(())
     }
 
-    /// Take a song from the list and make it go. Returns true if song was enqueued, false if not (e.g empty playlist)
-    pub fn enqueue(&mut self) -> ClientResult<bool> {
-        trace!("Reset skip votes to zero (next song enqueued)");
-        self.skip_votes = 0;
+    /// Fetch one page of search results, from the cache if possible
+    fn search_page(&mut self, params: &SearchParams) -> ClientResult<SearchResult> {
+        debug!("Searching for {:?}", params);
+        let market = self.market();
+        let limit = params.limit.unwrap_or(self.cfg.spotify.search_limit);
+        let mut query = params.query.clone();
+        add_search_filter(&mut query, "artist", &params.artist);
+        add_search_filter(&mut query, "album", &params.album);
 
-        if let Some(t) = self.the_list.nextup() {
-            trace!("Enqueuing song");
-            self.load_song(t)?;
-            self.status.state = PlaybackState::EnqueuedAndWaiting; // TODO: Is this state necessary?
+        // Results are cached before filtering, so the filters can be changed without searching again
+        let key = format!(
+            "search {:?} {:?} {:?} {} {}",
+            params.kind,
+            query,
+            market.as_ref().map(|m| m.as_str()),
+            params.offset,
+            limit
+        );
+        let mut sr = match self.search_cache.get(&key) {
+            Some(sr) => sr,
+            None => {
+                let start = Instant::now();
+                let sr = self.fetch_search(params.kind, &query, market, params.offset, limit)?;
+                trace!("Search took {}ms", duration_as_millis(start.elapsed()));
+                self.cache_tracks(&sr.items);
+                self.search_cache.insert(key, sr.clone());
+                sr
+            }
+        };
+        sr.items = filter_tracks(sr.items, params.playable_only, params.hide_explicit);
+        Ok(sr)
+    }
 
-            // Enqueued a song
-            Ok(true)
-        } else {
-            // No song in queue
-            Ok(false)
+    fn fetch_search(
+        &self,
+        kind: SearchKind,
+        query: &str,
+        market: Option<Country>,
+        offset: u32,
+        limit: u32,
+    ) -> ClientResult<SearchResult> {
+        let c = self.get_spotify()?;
+        let mut sr = SearchResult {
+            offset,
+            ..SearchResult::default()
+        };
+        let page_next;
+        match kind {
+            SearchKind::Track => {
+                let search = metrics::time_api("search_track", || {
+                    c.search_track(query, limit, offset, market)
+                })?;
+                sr.total = search.tracks.total;
+                page_next = search.tracks.next.is_some();
+                sr.items = search
+                    .tracks
+                    .items
+                    .into_iter()
+                    .map(BasicSongInfo::from)
+                    .collect();
+            }
+            SearchKind::Artist => {
+                let search = metrics::time_api("search_artist", || {
+                    c.search_artist(query, limit, offset, market)
+                })?;
+                sr.total = search.artists.total;
+                page_next = search.artists.next.is_some();
+                sr.artists = search
+                    .artists
+                    .items
+                    .into_iter()
+                    .map(ArtistInfo::from)
+                    .collect();
+            }
+            SearchKind::Album => {
+                let search = metrics::time_api("search_album", || {
+                    c.search_album(query, limit, offset, market)
+                })?;
+                sr.total = search.albums.total;
+                page_next = search.albums.next.is_some();
+                sr.albums = search
+                    .albums
+                    .items
+                    .into_iter()
+                    .map(AlbumInfo::from)
+                    .collect();
+            }
         }
+        if page_next {
+            sr.next_offset = Some(offset + limit);
+        }
+        Ok(sr)
     }
 
-    /// Called very often, performs regular activities like checking if Spotify is ready to play next song
-    pub fn routine(&mut self) -> ClientResult<()> {
-        {
-            // Wait a reasonable amount of time before pinging Spotify API for playback status
-            let time_for_thing = if let Some(lc) = self.last_status_check {
-                let x = lc.elapsed()?;
-                duration_as_millis(x) > self.status_check_interval_ms.into()
-            } else {
-                true
-            };
+    /// Responds with suggestions, unless the client has made a newer query. The queue is only
+    /// locked around checking for that and responding, so newer keystrokes can be queued while
+    /// Spotify is asked
+    pub fn suggest(&mut self, params: &SuggestParams, queue: &LockedTaskQueue) -> ClientResult<()> {
+        let superseded = || {
+            trace!("Skipping superseded suggestion query {:?}", params.prefix);
+            CommandResponseDataType::Suggest(SuggestResult::superseded(&params.prefix))
+        };
+        let latest = queue
+            .lock()
+            .unwrap()
+            .is_latest_suggestion(&params.client, params.tid);
+        let result = if latest {
+            Some(self.suggestions(&params.prefix))
+        } else {
+            None
+        };
 
-            if time_for_thing {
-                trace!("Checking status");
-                // Sufficent time has elapsed
-                self.last_status_check = Some(SystemTime::now());
+        let mut q = queue.lock().unwrap();
+        let value = match result {
+            // No point answering with suggestions for a query the client has moved on from
+            Some(_) if !q.is_latest_suggestion(&params.client, params.tid) => superseded(),
+            Some(Ok(r)) => CommandResponseDataType::Suggest(r),
+            Some(Err(e)) => CommandResponseDataType::Error(e.to_string()),
+            None => superseded(),
+        };
+        q.respond(CommandResponse {
+            tid: params.tid,
+            value,
+        });
+        Ok(())
+    }
 
-                // Update status
-                self.update_player_status()?;
+    /// Songs and artists starting with `prefix`. Matching songs from history come first
+    fn suggestions(&mut self, prefix: &str) -> ClientResult<SuggestResult> {
+        debug!("Suggestions for {:?}", prefix);
+        let cfg = self.cfg.suggest.clone();
+        let mut tracks: Vec<BasicSongInfo> = vec![];
+        let add = |tracks: &mut Vec<BasicSongInfo>, s: &BasicSongInfo| {
+            if tracks.len() < cfg.tracks as usize && !tracks.iter().any(|t| t.is_same_song(s)) {
+                tracks.push(s.clone());
+            }
+        };
 
-                // Enqueue song if needed
-                if self.status.state == PlaybackState::NeedsSong {
-                    self.enqueue()?;
-                }
+        // Most recently played first
+        for p in self.history.iter().rev() {
+            let s = &p.song;
+            if words_start_with(&s.title, prefix)
+                || s.artists.iter().any(|a| words_start_with(&a.name, prefix))
+            {
+                add(&mut tracks, s);
             }
         }
 
-        {
-            // Auth token refresh check
-            let time_for_refresh = if let Some(rt) = self.last_token_refresh {
+        let market = self.market();
+        let query = format!("{}*", prefix.trim());
+        let key = format!(
+            "suggest {:?} {:?} {} {}",
+            query,
+            market.as_ref().map(|m| m.as_str()),
+            cfg.tracks,
+            cfg.artists
+        );
+        let found = match self.search_cache.get(&key) {
+            Some(sr) => sr,
+            None => {
+                let mut sr = SearchResult::default();
+                if cfg.tracks > 0 {
+                    sr.items = self
+                        .fetch_search(SearchKind::Track, &query, market.clone(), 0, cfg.tracks)?
+                        .items;
+                    self.cache_tracks(&sr.items);
+                }
+                if cfg.artists > 0 {
+                    sr.artists = self
+                        .fetch_search(SearchKind::Artist, &query, market, 0, cfg.artists)?
+                        .artists;
+                }
+                self.search_cache.insert(key, sr.clone());
+                sr
+            }
+        };
+        for s in &found.items {
+            if s.is_playable != Some(false) && !self.cfg.playback.is_blocked(&s.title) {
+                add(&mut tracks, s);
+            }
+        }
+
+        Ok(SuggestResult {
+            prefix: prefix.to_string(),
+            tracks,
+            artists: found.artists,
+            superseded: false,
+        })
+    }
+
+    pub fn browse(&mut self, params: &BrowseParams, queue: &mut TaskQueue) -> ClientResult<()> {
+        let value = match self.browse_page(params) {
+            Ok(r) => CommandResponseDataType::Search(r),
+            Err(e) => CommandResponseDataType::Error(e.to_string()),
+        };
+        queue.respond(CommandResponse {
+            tid: params.tid,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Fetch one page of an artist's or album's contents, from the cache if possible
+    fn browse_page(&mut self, params: &BrowseParams) -> ClientResult<SearchResult> {
+        debug!("Browsing {:?}", params);
+        let market = self.market();
+        let limit = params.limit.unwrap_or(self.cfg.spotify.search_limit);
+        let key = format!(
+            "browse {:?} {:?} {} {}",
+            params.kind,
+            market.as_ref().map(|m| m.as_str()),
+            params.offset,
+            limit
+        );
+        let mut sr = match self.search_cache.get(&key) {
+            Some(sr) => sr,
+            None => {
+                let sr = self.fetch_browse(&params.kind, market, params.offset, limit)?;
+                // Album track lists leave out details like the ISRC, so only top tracks are
+                // complete enough to be requested without looking them up again
+                if let BrowseKind::ArtistTopTracks(_) = params.kind {
+                    self.cache_tracks(&sr.items);
+                }
+                self.search_cache.insert(key, sr.clone());
+                sr
+            }
+        };
+        sr.items = filter_tracks(sr.items, params.playable_only, params.hide_explicit);
+        Ok(sr)
+    }
+
+    fn fetch_browse(
+        &self,
+        kind: &BrowseKind,
+        market: Option<Country>,
+        offset: u32,
+        limit: u32,
+    ) -> ClientResult<SearchResult> {
+        let c = self.get_spotify()?;
+        let mut sr = SearchResult {
+            offset,
+            ..SearchResult::default()
+        };
+        match kind {
+            BrowseKind::ArtistTopTracks(id) => {
+                // Spotify requires a country here, rspotify defaults to US
+                let tracks =
+                    metrics::time_api("artist_top_tracks", || c.artist_top_tracks(id, market))?;
+                sr.total = tracks.tracks.len() as u32;
+                sr.items = tracks.tracks.into_iter().map(BasicSongInfo::from).collect();
+            }
+            BrowseKind::ArtistAlbums(id) => {
+                let albums = metrics::time_api("artist_albums", || {
+                    c.artist_albums(id, None, market, Some(limit), Some(offset))
+                })?;
+                sr.total = albums.total;
+                if albums.next.is_some() {
+                    sr.next_offset = Some(offset + limit);
+                }
+                sr.albums = albums.items.into_iter().map(AlbumInfo::from).collect();
+            }
+            BrowseKind::AlbumTracks(id) => {
+                // Track list leaves out the album, so look that up too
+                let album: AlbumInfo = metrics::time_api("album", || c.album(id))?.into();
+                let tracks = metrics::time_api("album_track", || c.album_track(id, limit, offset))?;
+                sr.total = tracks.total;
+                if tracks.next.is_some() {
+                    sr.next_offset = Some(offset + limit);
+                }
+                sr.items = tracks
+                    .items
+                    .into_iter()
+                    .map(|t| BasicSongInfo::from_album_track(t, &album))
+                    .collect();
+            }
+        }
+        Ok(sr)
+    }
+
+    /// Remember track details, so requesting them does not need to look them up again
+    fn cache_tracks(&mut self, tracks: &[BasicSongInfo]) {
+        for t in tracks {
+            self.track_cache.insert(t.spotify_uri.clone(), t.clone());
+        }
+    }
+
+    /// Track details, from the cache if possible
+    fn track_info(&mut self, track_id: &str) -> ClientResult<BasicSongInfo> {
+        let uri = if track_id.starts_with("spotify:track:") {
+            track_id.to_string()
+        } else {
+            format!("spotify:track:{}", track_id)
+        };
+        if let Some(t) = self.track_cache.get(&uri) {
+            return Ok(t);
+        }
+        let c = self.get_spotify()?;
+        let track: BasicSongInfo = metrics::time_api("track", || c.track(track_id))?.into();
+        self.track_cache.insert(uri, track.clone());
+        Ok(track)
+    }
+
+    /// Spotify's audio features for a track, if they are needed for smooth ordering. Songs are
+    /// still playable without them, so failure is only logged
+    fn audio_info(&self, track_id: &str) -> Option<AudioInfo> {
+        if self.cfg.playback.order != ListOrder::Smooth {
+            return None;
+        }
+        let c = self.get_spotify().ok()?;
+        match metrics::time_api("audio_features", || c.audio_features(track_id)) {
+            Ok(f) => Some(f.into()),
+            Err(e) => {
+                warn!("Could not get audio features for {}: {}", track_id, e);
+                None
+            }
+        }
+    }
+
+    /// Update `status` field
+    pub fn update_player_status(&mut self) -> ClientResult<()> {
+        let previous = self.our_song_progress();
+        self.status = if self.spotify.is_none() {
+            // No spotify API client
+            PlaybackStatus {
+                state: PlaybackState::NoAuth,
+                ..PlaybackStatus::default()
+            }
+        } else if self.device.is_none() {
+            // No active device
+            PlaybackStatus {
+                state: PlaybackState::NoDevice,
+                ..PlaybackStatus::default()
+            }
+        } else {
+            // Check what is playing
+            trace!("Querying current playing");
+            let c = self.get_spotify()?;
+            let x = metrics::time_api("current_playback", || c.current_playback(None))?;
+            let mut s = parse_playing_context(x);
+            if s.state == PlaybackState::Playing && !self.is_ours(&s) {
+                s.state = PlaybackState::External;
+            }
+            s
+        };
+        if self.status.state == PlaybackState::External {
+            self.note_interruption(&previous);
+        } else if self.status.state == PlaybackState::Playing {
+            // Back to our song, however that happened
+            self.interrupted = None;
+        }
+        self.last_status_update = Some(SystemTime::now());
+        self.status.restarts = self.restarts;
+        if self.status.progress_ms.is_some() {
+            self.status.sampled_at_ms = Some(unix_time_ms(SystemTime::now()));
+        }
+        self.status.interrupted = self.interrupted.clone();
+        self.status.radio = self.radio_enabled;
+        self.status.auto_picked = self.auto_picked_uri.is_some()
+            && self.status.song.as_ref().map(|s| &s.spotify_uri) == self.auto_picked_uri.as_ref();
+        self.update_schedule_status();
+        Ok(())
+    }
+
+    /// Remember our song if external playback replaced it part way through. A song which was
+    /// nearly over was most likely followed by Spotify's autoplay, so is not kept
+    fn note_interruption(&mut self, previous: &Option<InterruptedSong>) {
+        if let Some(p) = previous {
+            let remaining = p.song.duration_ms.saturating_sub(p.progress_ms);
+            if remaining > self.cfg.polling.end_window_ms {
+                info!(
+                    "{:?} interrupted by external playback at {}ms",
+                    p.song.title, p.progress_ms
+                );
+                self.interrupted = Some(p.clone());
+            }
+        }
+    }
+
+    /// If the song in `status` was started by Jukeula, either directly or via Spotify's queue
+    fn is_ours(&self, status: &PlaybackStatus) -> bool {
+        let uri = match &status.song {
+            Some(s) => &s.spotify_uri,
+            // Nothing identifiable playing, e.g an advert
+            None => return true,
+        };
+        self.last_started_uri.as_ref() == Some(uri)
+            || self.prequeued.as_ref().map(|p| &p.entry.song.spotify_uri) == Some(uri)
+    }
+
+    /// Check status in given number of milliseconds
+    fn poll_in(&mut self, ms: u32) {
+        self.last_status_check = Some(SystemTime::now());
+        self.next_status_check_ms = ms;
+    }
+
+    /// Check status on next call to `routine`, e.g after something changes which might need a new song
+    fn poll_now(&mut self) {
+        self.poll_in(0);
+    }
+
+    /// Check status once Spotify has had time to act on a playback command
+    fn poll_after_settle(&mut self) {
+        self.poll_in(std::cmp::max(self.cfg.polling.min_ms, SETTLE_MS));
+    }
+
+    /// Delay before next status check, based on what is currently happening
+    fn poll_delay_ms(&mut self) -> u32 {
+        let playing = self.status.state == PlaybackState::Playing
+            || self.status.state == PlaybackState::External;
+        if playing {
+            self.idle_since = None;
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+
+        let p = &self.cfg.polling;
+        match self.status.state {
+            PlaybackState::Playing | PlaybackState::External => {
+                match (&self.status.song, self.status.progress_ms) {
+                    (Some(s), Some(progress)) => {
+                        // Sleep until shortly before the song is predicted to end
+                        let remaining = s.duration_ms.saturating_sub(progress);
+                        let until_end_window = remaining.saturating_sub(p.end_window_ms);
+                        std::cmp::min(std::cmp::max(until_end_window, p.min_ms), p.max_ms)
+                    }
+                    _ => p.min_ms,
+                }
+            }
+            PlaybackState::EnqueuedAndWaiting => std::cmp::max(p.min_ms, SETTLE_MS),
+            _ => {
+                let idle_ms = self
+                    .idle_since
+                    .map(|t| duration_as_millis(t.elapsed()))
+                    .unwrap_or(0);
+                if idle_ms > p.idle_after_ms.into() {
+                    p.idle_ms
+                } else {
+                    p.paused_ms
+                }
+            }
+        }
+    }
+
+    /// Adds specified track to "the list for consideration"
+    pub fn request(&mut self, params: &SongRequestInfo, queue: &mut TaskQueue) -> ClientResult<()> {
+        let r = self.add_request(&params.track_id, &params.requester, &params.client);
+        queue.respond(CommandResponse {
+            tid: params.tid,
+            value: match &r {
+                Ok(result) => CommandResponseDataType::Request(result.clone()),
+                Err(e) => CommandResponseDataType::Error(e.to_string()),
+            },
+        });
+        r.map(|_| ())
+    }
+
+    /// Look up requested track and add it to the list, unless blocked or a duplicate
+    fn add_request(
+        &mut self,
+        track_id: &str,
+        requester: &str,
+        client: &str,
+    ) -> ClientResult<RequestResult> {
+        debug!("Requested song {} by {} ({})", track_id, requester, client);
+        let x = self.track_info(track_id)?;
+        if self.cfg.playback.is_blocked(&x.title) {
+            info!(
+                "Ignoring request for blocked song {:?} by {}",
+                x.title,
+                x.artist_names()
+            );
+            return Ok(RequestResult::Blocked(x));
+        }
+        if let Some((existing, location)) = self.find_duplicate(&x) {
+            info!(
+                "Ignoring request for {:?}, same as {:?} ({:?})",
+                x.spotify_uri, existing.spotify_uri, location
+            );
+            return Ok(RequestResult::Duplicate { existing, location });
+        }
+        let quota = self.quota_info(client);
+        if let Some((limit, reason)) = self.quota_exceeded(&quota, &x) {
+            info!(
+                "Ignoring request for {:?} by {} ({}): {}",
+                x.spotify_uri, requester, client, reason
+            );
+            return Ok(RequestResult::OverQuota {
+                limit,
+                reason,
+                quota,
+            });
+        }
+        let features = self.audio_info(&x.spotify_uri);
+        let now_ms = unix_time_ms(SystemTime::now());
+        self.the_list.add(ListEntry {
+            song: x.clone(),
+            requested_by: Some(requester.to_string()),
+            client: Some(client.to_string()),
+            requested_at_ms: now_ms,
+            features,
+        });
+        self.recent_requests.push_back((client.to_string(), now_ms));
+        if self.status.state != PlaybackState::Playing && self.status.state != PlaybackState::Paused
+        {
+            // Might be waiting for a song
+            self.poll_now();
+        }
+        Ok(RequestResult::Added(x))
+    }
+
+    /// Responds with client's use of their quota
+    pub fn quota(&mut self, params: &QuotaParams, queue: &mut TaskQueue) {
+        let quota = self.quota_info(&params.client);
+        queue.respond(CommandResponse {
+            tid: params.tid,
+            value: CommandResponseDataType::Quota(quota),
+        });
+    }
+
+    /// Count client's waiting songs and recent requests, against the `quota` config
+    fn quota_info(&mut self, client: &str) -> QuotaInfo {
+        let hour_ago_ms = unix_time_ms(SystemTime::now()).saturating_sub(60 * 60 * 1000);
+        while let Some((_, at_ms)) = self.recent_requests.front() {
+            if *at_ms >= hour_ago_ms {
+                break;
+            }
+            self.recent_requests.pop_front();
+        }
+
+        let client_owned = Some(client.to_string());
+        let pending: Vec<&ListEntry> = self
+            .the_list
+            .songs
+            .values()
+            .chain(self.prequeued.as_ref().map(|p| &p.entry))
+            .filter(|e| e.client == client_owned)
+            .collect();
+        let pending_songs = pending.len() as u32;
+        let pending_ms: u64 = pending.iter().map(|e| u64::from(e.song.duration_ms)).sum();
+        let requests_last_hour = self
+            .recent_requests
+            .iter()
+            .filter(|(c, _)| c == client)
+            .count() as u32;
+
+        let cfg = &self.cfg.quota;
+        let limit = |max: u32| if max == 0 { None } else { Some(max) };
+        QuotaInfo {
+            client: client.to_string(),
+            pending_songs,
+            pending_ms,
+            requests_last_hour,
+            remaining_songs: limit(cfg.max_pending).map(|m| m.saturating_sub(pending_songs)),
+            remaining_ms: limit(cfg.max_pending_minutes)
+                .map(|m| (u64::from(m) * 60 * 1000).saturating_sub(pending_ms)),
+            remaining_this_hour: limit(cfg.max_per_hour)
+                .map(|m| m.saturating_sub(requests_last_hour)),
+        }
+    }
+
+    /// Which limit adding `song` would go over, if any, and why
+    fn quota_exceeded(
+        &self,
+        quota: &QuotaInfo,
+        song: &BasicSongInfo,
+    ) -> Option<(QuotaLimit, String)> {
+        let cfg = &self.cfg.quota;
+        if quota.remaining_songs == Some(0) {
+            return Some((
+                QuotaLimit::PendingSongs,
+                format!(
+                    "You already have {} songs waiting to play, the limit is {}",
+                    quota.pending_songs, cfg.max_pending
+                ),
+            ));
+        }
+        if let Some(remaining_ms) = quota.remaining_ms {
+            if u64::from(song.duration_ms) > remaining_ms {
+                return Some((
+                    QuotaLimit::PendingDuration,
+                    format!(
+                        "Your waiting songs would add up to more than the limit of {} minutes",
+                        cfg.max_pending_minutes
+                    ),
+                ));
+            }
+        }
+        if quota.remaining_this_hour == Some(0) {
+            return Some((
+                QuotaLimit::PerHour,
+                format!(
+                    "You have requested {} songs in the last hour, the limit is {}",
+                    quota.requests_last_hour, cfg.max_per_hour
+                ),
+            ));
+        }
+        None
+    }
+
+    /// Find same song in the list, playing now or recently played
+    fn find_duplicate(&self, song: &BasicSongInfo) -> Option<(BasicSongInfo, DuplicateLocation)> {
+        let queued = self.the_list.find_same(song).or_else(|| {
+            self.prequeued
+                .as_ref()
+                .map(|p| &p.entry.song)
+                .filter(|s| s.is_same_song(song))
+        });
+        if let Some(s) = queued {
+            return Some((s.clone(), DuplicateLocation::List));
+        }
+
+        let state = self.status.state;
+        if state == PlaybackState::Playing
+            || state == PlaybackState::Paused
+            || state == PlaybackState::External
+        {
+            if let Some(s) = self.status.song.as_ref().filter(|s| s.is_same_song(song)) {
+                return Some((s.clone(), DuplicateLocation::Playing));
+            }
+        }
+
+        self.history
+            .iter()
+            .rev()
+            .find(|p| p.song.is_same_song(song))
+            .map(|p| (p.song.clone(), DuplicateLocation::History))
+    }
+
+    /// Add song to `history`, forgetting the oldest if full
+    fn record_played(&mut self, entry: &ListEntry, auto_picked: bool) {
+        self.auto_picked_uri = if auto_picked {
+            Some(entry.song.spotify_uri.clone())
+        } else {
+            None
+        };
+        self.history.push_back(PlayedSong {
+            song: entry.song.clone(),
+            played_at_ms: unix_time_ms(SystemTime::now()),
+            auto_picked,
+            requested_by: entry.requested_by.clone(),
+            features: entry.features,
+        });
+        while self.history.len() > self.cfg.playback.history_size {
+            self.history.pop_front();
+        }
+    }
+
+    /// Next song to play from the list, or else from radio mode if it is on. Also returns
+    /// whether it was picked by radio mode
+    fn next_song(&mut self) -> ClientResult<Option<(ListEntry, bool)>> {
+        let avoid = self.avoid();
+        let previous = match &self.prequeued {
+            Some(p) => p.entry.features,
+            None => self.history.back().and_then(|p| p.features),
+        };
+        if let Some(t) = self
+            .the_list
+            .nextup(&self.cfg.playback, &avoid, previous.as_ref())
+        {
+            return Ok(Some((t, false)));
+        }
+        if !self.radio_enabled {
+            return Ok(None);
+        }
+        if let Some(t) = self.radio_retry_at {
+            if Instant::now() < t {
+                return Ok(None);
+            }
+        }
+        let picked = self.radio_pick();
+        if let Ok(None) | Err(_) = picked {
+            self.radio_retry_at = Some(Instant::now() + Duration::from_millis(RADIO_RETRY_MS));
+        }
+        Ok(picked?.map(|song| {
+            let features = self.audio_info(&song.spotify_uri);
+            let entry = ListEntry {
+                song,
+                requested_by: None,
+                client: None,
+                requested_at_ms: unix_time_ms(SystemTime::now()),
+                features,
+            };
+            (entry, true)
+        }))
+    }
+
+    /// Songs to avoid playing next, according to `playback.avoid_same_artist` and
+    /// `playback.avoid_same_requester`
+    fn avoid(&self) -> Avoid {
+        let cfg = &self.cfg.playback;
+        let mut artists: Vec<String> = self
+            .history
+            .iter()
+            .rev()
+            .take(cfg.avoid_same_artist)
+            .flat_map(|p| p.song.artists.iter().map(|a| a.id.clone()))
+            .collect();
+        if let Some(p) = &self.prequeued {
+            // About to play, so counts as the most recent song
+            if cfg.avoid_same_artist > 0 {
+                artists.extend(p.entry.song.artists.iter().map(|a| a.id.clone()));
+            }
+        }
+        let requester = if cfg.avoid_same_requester {
+            match &self.prequeued {
+                Some(p) => p.entry.requested_by.clone(),
+                None => self.history.back().and_then(|p| p.requested_by.clone()),
+            }
+        } else {
+            None
+        };
+        Avoid { artists, requester }
+    }
+
+    /// Song similar to recently requested ones, from Spotify's recommendations. Skips blocked
+    /// songs, and songs played within `radio.cooldown_minutes`
+    fn radio_pick(&mut self) -> ClientResult<Option<BasicSongInfo>> {
+        let cfg = self.cfg.radio.clone();
+        // Seed from requested songs, most recent first, or from whatever played if there are none
+        let mut seeds: Vec<&BasicSongInfo> = self
+            .history
+            .iter()
+            .rev()
+            .filter(|p| !p.auto_picked)
+            .map(|p| &p.song)
+            .collect();
+        if seeds.is_empty() {
+            seeds = self.history.iter().rev().map(|p| &p.song).collect();
+        }
+        if seeds.is_empty() {
+            debug!("Radio has nothing to base recommendations on");
+            return Ok(None);
+        }
+        let seed_tracks: Vec<String> = seeds
+            .iter()
+            .take(cfg.seed_tracks)
+            .map(|s| s.spotify_uri.clone())
+            .collect();
+        let mut seed_artists: Vec<String> = vec![];
+        for a in seeds.iter().filter_map(|s| s.artists.first()) {
+            if seed_artists.len() < cfg.seed_artists && !seed_artists.contains(&a.id) {
+                seed_artists.push(a.id.clone());
+            }
+        }
+        let non_empty = |v: Vec<String>| if v.is_empty() { None } else { Some(v) };
+
+        let market = self.market();
+        let c = self.get_spotify()?;
+        let recommended = metrics::time_api("recommendations", || {
+            c.recommendations(
+                non_empty(seed_artists),
+                None,
+                non_empty(seed_tracks),
+                20,
+                market,
+                &serde_json::Map::new(),
+            )
+        })?;
+
+        let cooldown_ms = u64::from(cfg.cooldown_minutes) * 60 * 1000;
+        let now_ms = unix_time_ms(SystemTime::now());
+        for t in recommended.tracks {
+            // Album is not included, but is not needed for comparing
+            let candidate = BasicSongInfo::from_album_track(t, &AlbumInfo::default());
+            if self.cfg.playback.is_blocked(&candidate.title) {
+                continue;
+            }
+            let recently_played = self.history.iter().any(|p| {
+                now_ms.saturating_sub(p.played_at_ms) < cooldown_ms
+                    && p.song.is_same_song(&candidate)
+            });
+            let playing = self
+                .status
+                .song
+                .as_ref()
+                .map(|s| s.is_same_song(&candidate))
+                .unwrap_or(false);
+            if recently_played || playing {
+                continue;
+            }
+            let song = self.track_info(&candidate.spotify_uri)?;
+            info!("Radio picked {:?} by {}", song.title, song.artist_names());
+            return Ok(Some(song));
+        }
+        debug!("Radio found no suitable recommendations");
+        Ok(None)
+    }
+
+    /// Turn radio mode on or off
+    pub fn set_radio(&mut self, enabled: bool) {
+        info!("Radio mode {}", if enabled { "on" } else { "off" });
+        self.radio_enabled = enabled;
+        self.radio_retry_at = None;
+        self.status.radio = enabled;
+        self.poll_now();
+    }
+
+    /// Make a song start playing, replacing anything currently playing
+    pub fn load_song(&mut self, track: BasicSongInfo) -> ClientResult<()> {
+        trace!("Starting playback of song");
+        let id = self.device.clone().map(|x| x.id);
+        let c = self.get_spotify()?;
+        let uri = track.spotify_uri;
+        metrics::time_api("start_playback", || {
+            c.start_playback(id, None, Some(vec![uri.clone()]), None)
+        })?;
+        // Any copy left in Spotify's queue is still to come, see `check_stale_queued`
+        for s in self.stale_queued.iter_mut().filter(|s| s.uri == uri) {
+            s.started_directly = true;
+        }
+        self.last_started_uri = Some(uri);
+        self.poll_after_settle();
+        Ok(())
+    }
+
+    /// Take a song from the list and make it go. Returns true if song was enqueued, false if not (e.g empty playlist)
+    pub fn enqueue(&mut self) -> ClientResult<bool> {
+        trace!("Reset skip votes to zero (next song enqueued)");
+        self.skip_votes.clear();
+
+        if self.cfg.playback.auto_resume && self.interrupted.is_some() {
+            self.resume_interrupted()?;
+            Ok(true)
+        } else if self.prequeued.is_some() {
+            // Next song is already in Spotify's queue, so move on to it. Checked by `check_prequeued`
+            trace!("Skipping to pre-queued song");
+            let id = self.device.clone().map(|x| x.id);
+            let c = self.get_spotify()?;
+            metrics::time_api("next_track", || c.next_track(id))?;
+            self.poll_after_settle();
+            self.status.state = PlaybackState::EnqueuedAndWaiting;
+            Ok(true)
+        } else if let Some((t, auto_picked)) = self.next_song()? {
+            trace!("Enqueuing song");
+            self.load_song(t.song.clone())?;
+            self.record_played(&t, auto_picked);
+            if !auto_picked {
+                metrics::SONGS_PLAYED.inc();
+            }
+            self.status.state = PlaybackState::EnqueuedAndWaiting; // TODO: Is this state necessary?
+
+            // Enqueued a song
+            Ok(true)
+        } else {
+            // No song in queue
+            Ok(false)
+        }
+    }
+
+    /// In `gapless` mode, add the next song to Spotify's queue if the current one is nearly over
+    fn prequeue(&mut self) -> ClientResult<()> {
+        // Also used to take over from external playback at the end of its track
+        let takeover = self.status.state == PlaybackState::External
+            && self.cfg.playback.external == ExternalPolicy::AfterTrack;
+        if self.prequeued.is_some() || !(self.status.state == PlaybackState::Playing || takeover) {
+            return Ok(());
+        }
+        if self.cfg.playback.auto_resume && self.interrupted.is_some() {
+            // Interrupted song goes first, so nothing should be queued after the current one
+            return Ok(());
+        }
+        let (current_uri, remaining_ms) = match (&self.status.song, self.status.progress_ms) {
+            (Some(s), Some(p)) => (s.spotify_uri.clone(), s.duration_ms.saturating_sub(p)),
+            _ => return Ok(()),
+        };
+        if remaining_ms > self.cfg.playback.prequeue_ms {
+            return Ok(());
+        }
+        if !self.schedule_open_in(remaining_ms) {
+            // Would start after closing time
+            return Ok(());
+        }
+
+        if let Some((t, auto_picked)) = self.next_song()? {
+            debug!("{}ms remaining, pre-queuing {:?}", remaining_ms, t);
+            let id = self.device.clone().map(|x| x.id);
+            let c = self.get_spotify()?;
+            let r = metrics::time_api("add_to_queue", || {
+                spotify_ext::add_to_queue(c, &t.song.spotify_uri, id)
+            });
+            if let Err(e) = r {
+                // Put it back so it is not lost, and will be played the normal way
+                if !auto_picked {
+                    self.the_list.add(t);
+                }
+                return Err(e);
+            }
+            if !auto_picked {
+                metrics::SONGS_PLAYED.inc();
+            }
+            self.prequeued = Some(Prequeued {
+                entry: t,
+                after_uri: current_uri,
+                auto_picked,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the pre-queued song started once the previous one finished, starting it directly if not
+    fn check_prequeued(&mut self) -> ClientResult<()> {
+        let p = match &self.prequeued {
+            Some(p) => p.clone(),
+            None => return Ok(()),
+        };
+        let current_uri = self.status.song.as_ref().map(|s| s.spotify_uri.clone());
+        let state = self.status.state;
+
+        if current_uri.as_ref() == Some(&p.entry.song.spotify_uri)
+            && (state == PlaybackState::Playing || state == PlaybackState::Paused)
+        {
+            if self.status.schedule == ScheduleState::Closed {
+                // Paused by `stop_after_hours`, so keep it for tomorrow
+                info!("Pre-queued song started outside office hours, returning it to the list");
+                self.prequeued = None;
+                if !p.auto_picked {
+                    self.the_list.add(p.entry);
+                }
+                return Ok(());
+            }
+            debug!("Pre-queued song started as expected");
+            self.record_played(&p.entry, p.auto_picked);
+            self.last_started_uri = Some(p.entry.song.spotify_uri);
+            self.prequeued = None;
+            self.skip_votes.clear();
+        } else if state == PlaybackState::NeedsSong
+            || (current_uri.is_some() && current_uri != Some(p.after_uri.clone()))
+        {
+            // Either playback stopped, or something else started
+            if state == PlaybackState::External
+                && self.cfg.playback.external == ExternalPolicy::Wait
+            {
+                info!("External playback started, not starting pre-queued song");
+                self.forget_prequeued();
+                return Ok(());
+            }
+            if !self.playback_allowed() {
+                info!("Playback not allowed now, not starting pre-queued song");
+                self.forget_prequeued();
+                return Ok(());
+            }
+            warn!(
+                "Expected pre-queued song {:?} to be playing, but found {:?} ({:?}). Starting it directly",
+                p.entry.song.spotify_uri, current_uri, state
+            );
+            self.prequeued = None;
+            self.add_stale_queued(&p.entry.song.spotify_uri);
+            self.skip_votes.clear();
+            self.load_song(p.entry.song.clone())?;
+            self.record_played(&p.entry, p.auto_picked);
+            self.status.state = PlaybackState::EnqueuedAndWaiting;
+        }
+        // Otherwise the previous song is still playing
+        Ok(())
+    }
+
+    /// Return pre-queued song to the list, e.g when the device it was queued on goes away. It
+    /// stays in Spotify's queue, so is reconciled if it starts from there later
+    fn forget_prequeued(&mut self) {
+        if let Some(p) = self.prequeued.take() {
+            self.add_stale_queued(&p.entry.song.spotify_uri);
+            if p.auto_picked {
+                debug!("Forgetting pre-queued radio song {:?}", p.entry.song);
+            } else {
+                debug!("Returning pre-queued song {:?} to the list", p.entry.song);
+                self.the_list.add(p.entry);
+            }
+        }
+    }
+
+    fn add_stale_queued(&mut self, uri: &str) {
+        self.stale_queued.push_back(StaleQueued {
+            uri: uri.to_string(),
+            started_directly: false,
+        });
+        while self.stale_queued.len() > MAX_STALE_QUEUED {
+            self.stale_queued.pop_front();
+        }
+    }
+
+    /// Deal with a song in `stale_queued` starting from Spotify's queue, so it does not play twice.
+    /// `previous` is the song and position at the previous status check
+    fn check_stale_queued(&mut self, previous: Option<(String, u32)>) -> ClientResult<()> {
+        let current = match (&self.status.song, self.status.progress_ms) {
+            (Some(s), Some(p)) => Some((s.spotify_uri.clone(), p)),
+            _ => None,
+        };
+        let previous = previous.as_ref().map(|(u, p)| (u.as_str(), *p));
+        let current = current.as_ref().map(|(u, p)| (u.as_str(), *p));
+        let found = self.stale_queued.iter().enumerate().find_map(|(i, s)| {
+            let in_list = self.the_list.songs.contains_key(&s.uri);
+            reconcile_stale(s, previous, current, in_list).map(|a| (i, a))
+        });
+        let (i, action) = match found {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        let stale = &mut self.stale_queued[i];
+        match action {
+            StaleAction::Expect => {
+                trace!("Directly started {:?} is playing", stale.uri);
+                stale.started_directly = false;
+            }
+            StaleAction::Adopt => {
+                let uri = stale.uri.clone();
+                self.stale_queued.remove(i);
+                info!(
+                    "{:?} started from Spotify's queue, taking it from the list",
+                    uri
+                );
+                if let Some(entry) = self.the_list.take(&uri) {
+                    metrics::SONGS_PLAYED.inc();
+                    self.record_played(&entry, false);
+                }
+                self.last_started_uri = Some(uri);
+                self.skip_votes.clear();
+                if self.status.state == PlaybackState::External {
+                    self.status.state = PlaybackState::Playing;
+                }
+            }
+            StaleAction::Skip => {
+                let uri = stale.uri.clone();
+                self.stale_queued.remove(i);
+                info!("{:?} started again from Spotify's queue, skipping it", uri);
+                if !self.playback_allowed() {
+                    // Whatever is next in Spotify's queue should not play either
+                    return self.pause();
+                }
+                let id = self.device.clone().map(|x| x.id);
+                let c = self.get_spotify()?;
+                metrics::time_api("next_track", || c.next_track(id))?;
+                self.poll_after_settle();
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide whether to take over from external playback, according to `playback.external`
+    fn handle_external(&mut self) -> ClientResult<()> {
+        if self.status.state != PlaybackState::External {
+            self.external_uri = None;
+            return Ok(());
+        }
+        let current_uri = self.status.song.as_ref().map(|s| s.spotify_uri.clone());
+        if self.external_uri.is_none() {
+            info!("External playback detected: {:?}", current_uri);
+            self.external_uri = current_uri.clone();
+        }
+        let resume_pending = self.cfg.playback.auto_resume && self.interrupted.is_some();
+        if self.the_list.songs.is_empty() && !resume_pending {
+            // Nothing to replace it with
+            return Ok(());
+        }
+
+        let interrupt = match self.cfg.playback.external {
+            ExternalPolicy::Wait => false,
+            ExternalPolicy::Immediately => true,
+            // Next track started without us managing to pre-queue, e.g `gapless` is disabled
+            ExternalPolicy::AfterTrack => {
+                self.prequeued.is_none() && current_uri != self.external_uri
+            }
+        };
+        if interrupt {
+            info!("Taking over from external playback");
+            self.external_uri = None;
+            self.enqueue()?;
+        }
+        Ok(())
+    }
+
+    /// Register a client's vote to skip the current song, skipping it once enough different
+    /// clients have voted
+    pub fn vote_skip(&mut self, client: &str) -> ClientResult<()> {
+        let current_uri = self.status.song.as_ref().map(|s| s.spotify_uri.clone());
+        if current_uri != self.skip_votes_uri {
+            // Votes were for a song which has since changed
+            self.skip_votes.clear();
+            self.skip_votes_uri = current_uri;
+        }
+        if !self.skip_votes.insert(client.to_string()) {
+            debug!("{} already voted to skip", client);
+            return Ok(());
+        }
+        debug!(
+            "Skip votes now {}/{}",
+            self.skip_votes.len(),
+            self.cfg.playback.skip_threshold
+        );
+        if self.skip_votes.len() as u32 >= self.cfg.playback.skip_threshold {
+            info!("Skipping current song");
+            if !self.playback_allowed() {
+                self.skip_votes.clear();
+                self.pause()?;
+            } else if !self.enqueue()? {
+                // Nothing else to play
+                self.pause()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Update status and start the next song if needed
+    fn check_status(&mut self) -> ClientResult<()> {
+        // Pick up preferred device again if it went missing
+        self.reselect_device()?;
+
+        // Update status
+        let previous = match (&self.status.song, self.status.progress_ms) {
+            (Some(s), Some(p)) => Some((s.spotify_uri.clone(), p)),
+            _ => None,
+        };
+        self.update_player_status()?;
+
+        // Change volume for time of day
+        self.apply_volume_profile()?;
+
+        // Pause or turn down for meetings etc
+        self.update_quiet_period()?;
+
+        // Make sure song put in Spotify's queue was played, and only once. Still needed outside
+        // office hours, as the song playing when they end may be followed by a queued one
+        self.check_prequeued()?;
+        self.check_stale_queued(previous.clone())?;
+
+        if self.status.schedule == ScheduleState::Closed {
+            // Outside office hours, so let the current song finish and play nothing more
+            return self.stop_after_hours(previous);
+        }
+        if self.schedule_paused {
+            self.schedule_paused = false;
+            if self.status.state == PlaybackState::Paused {
+                // Start the day from the list, rather than what was paused overnight
+                self.status.state = PlaybackState::NeedsSong;
+            }
+        }
+        if !self.playback_allowed() {
+            // In a quiet period, so play nothing more
+            return Ok(());
+        }
+
+        // Take over from something else playing, depending on policy
+        self.handle_external()?;
+
+        // Enqueue song if needed
+        if self.status.state == PlaybackState::NeedsSong {
+            self.enqueue()?;
+        } else if self.cfg.playback.gapless {
+            self.prequeue()?;
+        }
+        Ok(())
+    }
+
+    /// Called very often, performs regular activities like checking if Spotify is ready to play next song
+    pub fn routine(&mut self) -> ClientResult<()> {
+        {
+            // Wait a reasonable amount of time before pinging Spotify API for playback status
+            let time_for_thing = if let Some(lc) = self.last_status_check {
+                let x = lc.elapsed()?;
+                duration_as_millis(x) > self.next_status_check_ms.into()
+            } else {
+                true
+            };
+
+            if time_for_thing {
+                trace!("Checking status");
+                // Sufficent time has elapsed
+                self.last_status_check = Some(SystemTime::now());
+
+                if let Err(e) = self.check_status() {
+                    // Could be because the device disappeared
+                    self.check_device_present();
+                    return Err(e);
+                }
+
+                self.next_status_check_ms = self.poll_delay_ms();
+                trace!("Next status check in {}ms", self.next_status_check_ms);
+            }
+        }
+
+        {
+            // Auth token refresh check
+            let time_for_refresh = if let Some(rt) = self.last_token_refresh {
                 let x = rt.elapsed()?;
-                duration_as_millis(x) > self.token_refresh_interval_ms.into()
+                duration_as_millis(x) > self.cfg.spotify.token_refresh_interval_ms.into()
             } else {
                 true
             };
             if time_for_refresh {
-                if let None = self.spotify {
+                if self.spotify.is_none() {
                     // Not yet authenticated, so refreshign token is no-op
                 } else {
                     debug!("Time to refresh auth token");
@@ -353,3 +2226,191 @@ impl Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(uri: &str, artist: &str, requester: &str) -> ListEntry {
+        ListEntry {
+            song: BasicSongInfo {
+                spotify_uri: uri.into(),
+                title: uri.into(),
+                artists: vec![ArtistInfo {
+                    id: artist.into(),
+                    name: artist.into(),
+                }],
+                album: AlbumInfo::default(),
+                duration_ms: 180_000,
+                isrc: None,
+                explicit: false,
+                popularity: 50,
+                is_playable: None,
+            },
+            requested_by: Some(requester.into()),
+            client: None,
+            requested_at_ms: 0,
+            features: None,
+        }
+    }
+
+    fn list(entries: Vec<ListEntry>) -> TheList {
+        let mut l = TheList::new();
+        for e in entries {
+            l.add(e);
+        }
+        l
+    }
+
+    fn pick(l: &mut TheList, avoid: &Avoid) -> String {
+        let cfg = PlaybackConfig::default();
+        l.nextup(&cfg, avoid, None).unwrap().song.spotify_uri
+    }
+
+    #[test]
+    fn avoid_recent_artist() {
+        let avoid = Avoid {
+            artists: vec!["a".into(), "b".into()],
+            requester: None,
+        };
+        for _ in 0..10 {
+            let mut l = list(vec![
+                entry("1", "a", "x"),
+                entry("2", "b", "x"),
+                entry("3", "c", "x"),
+            ]);
+            assert_eq!(pick(&mut l, &avoid), "3");
+        }
+    }
+
+    #[test]
+    fn avoid_last_requester() {
+        let avoid = Avoid {
+            artists: vec![],
+            requester: Some("x".into()),
+        };
+        for _ in 0..10 {
+            let mut l = list(vec![
+                entry("1", "a", "x"),
+                entry("2", "b", "y"),
+                entry("3", "c", "x"),
+            ]);
+            assert_eq!(pick(&mut l, &avoid), "2");
+        }
+    }
+
+    #[test]
+    fn avoid_artist_before_requester() {
+        // Nothing passes both, so only the artist is avoided
+        let avoid = Avoid {
+            artists: vec!["a".into()],
+            requester: Some("x".into()),
+        };
+        for _ in 0..10 {
+            let mut l = list(vec![entry("1", "a", "y"), entry("2", "b", "x")]);
+            assert_eq!(pick(&mut l, &avoid), "2");
+        }
+    }
+
+    #[test]
+    fn avoid_falls_back_when_everything_avoided() {
+        let avoid = Avoid {
+            artists: vec!["a".into()],
+            requester: Some("x".into()),
+        };
+        let mut l = list(vec![entry("1", "a", "x"), entry("2", "a", "x")]);
+        let first = pick(&mut l, &avoid);
+        let second = pick(&mut l, &avoid);
+        assert_ne!(first, second);
+        assert!(l.nextup(&PlaybackConfig::default(), &avoid, None).is_none());
+    }
+
+    #[test]
+    fn avoid_from_history() {
+        let mut cfg = Config::default();
+        cfg.playback.avoid_same_artist = 2;
+        let mut c = Client::new(&cfg);
+        for (uri, artist, requester) in &[("1", "a", "x"), ("2", "b", "y"), ("3", "c", "z")] {
+            c.record_played(&entry(uri, artist, requester), false);
+        }
+        let avoid = c.avoid();
+        assert_eq!(avoid.artists, vec!["c".to_string(), "b".to_string()]);
+        assert_eq!(avoid.requester, Some("z".into()));
+
+        c.cfg.playback.avoid_same_artist = 0;
+        c.cfg.playback.avoid_same_requester = false;
+        let avoid = c.avoid();
+        assert!(avoid.artists.is_empty());
+        assert_eq!(avoid.requester, None);
+    }
+
+    fn stale(started_directly: bool) -> StaleQueued {
+        StaleQueued {
+            uri: "spotify:track:a".into(),
+            started_directly,
+        }
+    }
+
+    #[test]
+    fn stale_song_starting_after_another() {
+        let previous = Some(("spotify:track:b", 200_000));
+        let current = Some(("spotify:track:a", 1000));
+        assert_eq!(
+            reconcile_stale(&stale(false), previous, current, true),
+            Some(StaleAction::Adopt)
+        );
+        assert_eq!(
+            reconcile_stale(&stale(false), previous, current, false),
+            Some(StaleAction::Skip)
+        );
+        assert_eq!(
+            reconcile_stale(&stale(true), previous, current, false),
+            Some(StaleAction::Expect)
+        );
+    }
+
+    #[test]
+    fn stale_song_starting_again_after_itself() {
+        // Played directly, then its copy in Spotify's queue followed straight after
+        let previous = Some(("spotify:track:a", 200_000));
+        let current = Some(("spotify:track:a", 1000));
+        assert_eq!(
+            reconcile_stale(&stale(false), previous, current, false),
+            Some(StaleAction::Skip)
+        );
+    }
+
+    #[test]
+    fn stale_song_still_playing() {
+        let previous = Some(("spotify:track:a", 10_000));
+        let current = Some(("spotify:track:a", 12_000));
+        assert_eq!(
+            reconcile_stale(&stale(false), previous, current, false),
+            None
+        );
+        // Seeking back a little is not starting again
+        let current = Some(("spotify:track:a", 8000));
+        assert_eq!(
+            reconcile_stale(&stale(false), previous, current, false),
+            None
+        );
+    }
+
+    #[test]
+    fn other_song_playing() {
+        let previous = Some(("spotify:track:a", 10_000));
+        assert_eq!(
+            reconcile_stale(&stale(false), previous, Some(("spotify:track:c", 0)), true),
+            None
+        );
+        assert_eq!(reconcile_stale(&stale(false), previous, None, true), None);
+    }
+
+    #[test]
+    fn stale_song_starting_from_stopped() {
+        assert_eq!(
+            reconcile_stale(&stale(false), None, Some(("spotify:track:a", 0)), true),
+            Some(StaleAction::Adopt)
+        );
+    }
+}
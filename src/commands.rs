@@ -1,12 +1,22 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use crate::common::{CommandResponse, SpotifyCommand, TaskID};
+use crate::common::{
+    CommandResponse, CommandResponseDataType, SpotifyCommand, SuggestParams, SuggestResult, TaskID,
+};
+use crate::metrics;
 
 #[derive(Default, Debug)]
 pub struct TaskQueue {
-    queue: std::collections::VecDeque<SpotifyCommand>,
+    /// Commands, along with when they were queued
+    queue: std::collections::VecDeque<(Instant, SpotifyCommand)>,
     responses: std::collections::VecDeque<CommandResponse>,
+    /// Tasks nobody is waiting for any more, whose responses are dropped
+    abandoned: std::collections::HashSet<TaskID>,
     last_task_id: u64,
+    /// Most recent suggestion query from each web client
+    latest_suggestions: HashMap<String, TaskID>,
 }
 
 impl TaskQueue {
@@ -20,7 +30,8 @@ impl TaskQueue {
         }
     }
     pub fn queue(&mut self, c: SpotifyCommand) {
-        self.queue.push_back(c);
+        self.queue.push_back((Instant::now(), c));
+        metrics::TASK_QUEUE_DEPTH.set(self.queue.len() as i64);
     }
     pub fn wait(&mut self, task_id: TaskID) -> Option<CommandResponse> {
         let mut idx: Option<usize> = None;
@@ -36,11 +47,58 @@ impl TaskQueue {
         }
         None
     }
+    /// Stop waiting for a task, so its response is not kept if it comes later
+    pub fn abandon(&mut self, task_id: TaskID) {
+        if self.wait(task_id).is_none() {
+            self.abandoned.insert(task_id);
+        }
+    }
     pub fn respond(&mut self, value: CommandResponse) {
-        self.responses.push_back(value)
+        if !self.abandoned.remove(&value.tid) {
+            self.responses.push_back(value)
+        }
+    }
+    /// Get task ID for a suggestion query, which supersedes any earlier ones from the same client
+    pub fn start_suggestion(&mut self, client: &str) -> TaskID {
+        let tid = self.get_task_id();
+        self.latest_suggestions.insert(client.to_string(), tid);
+        tid
+    }
+    /// If the client has not made a newer suggestion query since this one
+    pub fn is_latest_suggestion(&self, client: &str, tid: TaskID) -> bool {
+        self.latest_suggestions.get(client) == Some(&tid)
+    }
+    /// Forget about client, if this was its latest suggestion query
+    pub fn finish_suggestion(&mut self, client: &str, tid: TaskID) {
+        if self.is_latest_suggestion(client, tid) {
+            self.latest_suggestions.remove(client);
+        }
+    }
+    /// Queue suggestion query. Older queries from the same client which are still waiting are
+    /// answered straight away as superseded
+    pub fn queue_suggestion(&mut self, params: SuggestParams) {
+        let mut kept = std::collections::VecDeque::new();
+        for (queued, c) in self.queue.drain(..) {
+            match c {
+                SpotifyCommand::Suggest(old) if old.client == params.client => {
+                    self.responses.push_back(CommandResponse {
+                        tid: old.tid,
+                        value: CommandResponseDataType::Suggest(SuggestResult::superseded(
+                            &old.prefix,
+                        )),
+                    });
+                }
+                c => kept.push_back((queued, c)),
+            }
+        }
+        self.queue = kept;
+        self.queue(SpotifyCommand::Suggest(params));
     }
     pub fn pop(&mut self) -> Option<SpotifyCommand> {
-        self.queue.pop_back()
+        let (queued, c) = self.queue.pop_back()?;
+        metrics::TASK_QUEUE_DEPTH.set(self.queue.len() as i64);
+        metrics::TASK_QUEUE_WAIT.observe(queued.elapsed().as_secs_f64());
+        Some(c)
     }
 }
 
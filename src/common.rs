@@ -1,17 +1,14 @@
+use std::time::SystemTime;
+
 use failure::Error;
 use rspotify::spotify::oauth2::TokenInfo;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::config::Config;
+
 /// Shortcut for error return type
 pub type ClientResult<T> = Result<T, Error>;
 
-/// App configuration
-pub struct Config {
-    /// 0.0.0.0:8888
-    pub web_host: String,
-    pub web_port: u32,
-}
-
 /// State of the Spotify client
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum PlaybackState {
@@ -35,20 +32,273 @@ pub enum PlaybackState {
 
     /// Was in `NeedsSong` but we have put a song in the queue
     EnqueuedAndWaiting,
+
+    /// Playing something Jukeula did not start, e.g a playlist started directly in Spotify
+    External,
 }
 
+#[derive(Hash, Eq, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArtistInfo {
+    /// Spotify ID
+    pub id: String,
+    pub name: String,
+}
+
+impl From<rspotify::spotify::model::artist::SimplifiedArtist> for ArtistInfo {
+    fn from(a: rspotify::spotify::model::artist::SimplifiedArtist) -> ArtistInfo {
+        ArtistInfo {
+            id: a.id,
+            name: a.name,
+        }
+    }
+}
+
+impl From<rspotify::spotify::model::artist::FullArtist> for ArtistInfo {
+    fn from(a: rspotify::spotify::model::artist::FullArtist) -> ArtistInfo {
+        ArtistInfo {
+            id: a.id,
+            name: a.name,
+        }
+    }
+}
+
+#[derive(Hash, Eq, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Hash, Eq, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AlbumInfo {
+    /// Spotify ID
+    pub id: String,
+    pub name: String,
+    pub release_year: Option<u32>,
+    /// Album artwork in all available sizes, largest first
+    pub images: Vec<ImageInfo>,
+    #[serde(default)]
+    pub artists: Vec<ArtistInfo>,
+}
+
+impl From<rspotify::spotify::model::image::Image> for ImageInfo {
+    fn from(i: rspotify::spotify::model::image::Image) -> ImageInfo {
+        ImageInfo {
+            url: i.url,
+            width: i.width,
+            height: i.height,
+        }
+    }
+}
+
+/// Release date is "YYYY", "YYYY-MM" or "YYYY-MM-DD" depending on precision
+fn release_year(release_date: &str) -> Option<u32> {
+    release_date.get(..4).and_then(|y| y.parse().ok())
+}
+
+impl From<rspotify::spotify::model::album::SimplifiedAlbum> for AlbumInfo {
+    fn from(a: rspotify::spotify::model::album::SimplifiedAlbum) -> AlbumInfo {
+        AlbumInfo {
+            id: a.id,
+            name: a.name,
+            release_year: release_year(&a.release_date),
+            images: a.images.into_iter().map(ImageInfo::from).collect(),
+            artists: a.artists.into_iter().map(ArtistInfo::from).collect(),
+        }
+    }
+}
+
+impl From<rspotify::spotify::model::album::FullAlbum> for AlbumInfo {
+    fn from(a: rspotify::spotify::model::album::FullAlbum) -> AlbumInfo {
+        AlbumInfo {
+            id: a.id,
+            name: a.name,
+            release_year: release_year(&a.release_date),
+            images: a.images.into_iter().map(ImageInfo::from).collect(),
+            artists: a.artists.into_iter().map(ArtistInfo::from).collect(),
+        }
+    }
+}
+
+/// Fields added after the first version default to empty, so older state files still load
 #[derive(Hash, Eq, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BasicSongInfo {
     /// Spotify ID
     pub spotify_uri: String,
     /// Song title
     pub title: String,
-    /// Artist name
-    pub artist: String, // FIXME: Keep as list
+    #[serde(default)]
+    pub artists: Vec<ArtistInfo>,
+    #[serde(default)]
+    pub album: AlbumInfo,
     /// Song duration in milliseconds
     pub duration_ms: u32,
-    /// Album artwork
-    pub album_image_url: Option<String>,
+    /// International Standard Recording Code, identifying the recording across releases
+    #[serde(default)]
+    pub isrc: Option<String>,
+    #[serde(default)]
+    pub explicit: bool,
+    /// Spotify's popularity score, 0 to 100
+    #[serde(default)]
+    pub popularity: u32,
+    /// Only known if a market was given when fetching the track
+    #[serde(default)]
+    pub is_playable: Option<bool>,
+}
+
+/// Spotify's analysis of how a song sounds, used to order the list smoothly
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AudioInfo {
+    /// Beats per minute
+    pub tempo: f32,
+    /// Perceived intensity, from 0.0 to 1.0
+    pub energy: f32,
+    /// Musical positiveness, from 0.0 (sad, angry) to 1.0 (happy, cheerful)
+    pub valence: f32,
+    /// Pitch class (0 = C, 1 = C#, ...), or -1 if unknown
+    pub key: i32,
+}
+
+/// Tempo difference (in BPM) which counts as much as going from no energy to full energy
+const TEMPO_SCALE: f32 = 100.0;
+
+impl AudioInfo {
+    /// How jarring it would be to go from one song to the other, based on energy and tempo
+    pub fn distance(&self, other: &AudioInfo) -> f32 {
+        (self.energy - other.energy).abs() + (self.tempo - other.tempo).abs() / TEMPO_SCALE
+    }
+}
+
+impl From<rspotify::spotify::model::audio::AudioFeatures> for AudioInfo {
+    fn from(f: rspotify::spotify::model::audio::AudioFeatures) -> AudioInfo {
+        AudioInfo {
+            tempo: f.tempo,
+            energy: f.energy,
+            valence: f.valence,
+            key: f.key,
+        }
+    }
+}
+
+/// Song waiting in the list, along with who asked for it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListEntry {
+    /// Song fields are at the top level, so lists saved before entries had extra details still load
+    #[serde(flatten)]
+    pub song: BasicSongInfo,
+    /// Name given by the web client, or an anonymous ID based on its address
+    #[serde(default)]
+    pub requested_by: Option<String>,
+    /// Anonymous ID based on the requesting client's address, which quotas are counted against
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Milliseconds since Unix epoch when requested
+    #[serde(default)]
+    pub requested_at_ms: u64,
+    /// Missing if Spotify has no analysis of the song
+    #[serde(default)]
+    pub features: Option<AudioInfo>,
+}
+
+/// Words which mark a different release of the same recording, when in brackets or after " - "
+/// Matched as whole words, so "(feat. Stereolab)" is not a "stereo" release
+static RELEASE_MARKERS: &[&str] = &[
+    "remaster",
+    "remastered",
+    "single version",
+    "album version",
+    "radio edit",
+    "mono",
+    "stereo",
+    "deluxe",
+    "anniversary",
+    "bonus track",
+    "explicit",
+    "clean",
+];
+
+/// Lowercase title without release markers, e.g "Song (2011 Remaster)" becomes "song"
+pub fn normalize_title(title: &str) -> String {
+    let lower = title.to_lowercase();
+    let is_marker = |s: &str| {
+        let words = format!(" {} ", normalize_name(s));
+        RELEASE_MARKERS
+            .iter()
+            .any(|m| words.contains(&format!(" {} ", m)))
+    };
+
+    // Remove bracketed parts and " - " suffixes which mention a marker
+    let mut t = String::new();
+    let mut rest = lower.as_str();
+    while let Some(open) = rest.find(&['(', '['][..]) {
+        let close = if rest[open..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        match rest[open..].find(close) {
+            Some(len) if is_marker(&rest[open..open + len]) => {
+                t.push_str(&rest[..open]);
+                rest = &rest[open + len + 1..];
+            }
+            _ => {
+                t.push_str(&rest[..=open]);
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    t.push_str(rest);
+    if let Some(dash) = t.find(" - ") {
+        if is_marker(&t[dash..]) {
+            t.truncate(dash);
+        }
+    }
+
+    normalize_name(&t)
+}
+
+/// Lowercase with only letters and numbers, separated by single spaces
+pub fn normalize_name(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// If any word of `text` starts with `prefix`, ignoring case and punctuation
+pub fn words_start_with(text: &str, prefix: &str) -> bool {
+    let text = normalize_name(text);
+    let prefix = normalize_name(prefix);
+    !prefix.is_empty() && (text.starts_with(&prefix) || text.contains(&format!(" {}", prefix)))
+}
+
+impl BasicSongInfo {
+    /// Artist names joined together, for display
+    pub fn artist_names(&self) -> String {
+        self.artists
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+
+    /// If both are the same recording, possibly from different releases (e.g single and album).
+    /// The same if the ISRCs match, or else if the main artist and title match. Names are compared
+    /// even when both have different ISRCs, as re-releases often get a new one
+    pub fn is_same_song(&self, other: &BasicSongInfo) -> bool {
+        if self.spotify_uri == other.spotify_uri {
+            return true;
+        }
+        if let (Some(a), Some(b)) = (&self.isrc, &other.isrc) {
+            if a.eq_ignore_ascii_case(b) {
+                return true;
+            }
+        }
+        let main_artist = |s: &BasicSongInfo| s.artists.first().map(|a| normalize_name(&a.name));
+        main_artist(self) == main_artist(other)
+            && normalize_title(&self.title) == normalize_title(&other.title)
+    }
 }
 
 impl From<rspotify::spotify::model::track::FullTrack> for BasicSongInfo {
@@ -56,18 +306,86 @@ impl From<rspotify::spotify::model::track::FullTrack> for BasicSongInfo {
         BasicSongInfo {
             spotify_uri: ft.uri,
             title: ft.name,
-            artist: ft
-                .artists
-                .iter()
-                .map(|t| t.name.clone())
-                .collect::<Vec<String>>()
-                .join(", "),
+            artists: ft.artists.into_iter().map(ArtistInfo::from).collect(),
+            album: ft.album.into(),
             duration_ms: ft.duration_ms,
-            album_image_url: ft.album.images.first().and_then(|i| Some(i.url.clone())),
+            isrc: ft.external_ids.get("isrc").cloned(),
+            explicit: ft.explicit,
+            popularity: ft.popularity,
+            is_playable: ft.is_playable,
+        }
+    }
+}
+
+impl BasicSongInfo {
+    /// Track from an album's track list, which leaves out the album and some details
+    pub fn from_album_track(
+        t: rspotify::spotify::model::track::SimplifiedTrack,
+        album: &AlbumInfo,
+    ) -> BasicSongInfo {
+        BasicSongInfo {
+            spotify_uri: t.uri,
+            title: t.name,
+            artists: t.artists.into_iter().map(ArtistInfo::from).collect(),
+            album: album.clone(),
+            duration_ms: t.duration_ms,
+            isrc: None,
+            explicit: t.explicit,
+            popularity: 0,
+            is_playable: None,
         }
     }
 }
 
+/// Convert `Duration` into milliseconds (as u64), to be used until
+/// the `as_millis` method is stable (returns u128). Max `u64` milliseconds
+/// is only 49 days whereas `u128` is only 10^28 years..
+/// Enough for our purposes
+pub fn duration_as_millis(d: std::time::Duration) -> u64 {
+    // TOOD: Replace when Duration::as_millis becomes stable
+    (d.as_secs() * 1000) + u64::from(d.subsec_millis())
+}
+
+/// Milliseconds since the Unix epoch
+pub fn unix_time_ms(t: SystemTime) -> u64 {
+    t.duration_since(std::time::UNIX_EPOCH)
+        .map(duration_as_millis)
+        .unwrap_or(0)
+}
+
+/// Whether the `schedule` config allows songs from the list to be played right now
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum ScheduleState {
+    /// No schedule configured
+    Unrestricted,
+
+    /// Within scheduled hours
+    Open,
+
+    /// Outside scheduled hours, the current song is allowed to finish but nothing more is played
+    Closed,
+
+    /// Schedule temporarily bypassed by an admin
+    Overridden,
+}
+
+/// Calendar event during which playback is paused or quieter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuietPeriod {
+    /// Event summary
+    pub summary: String,
+    /// Milliseconds since Unix epoch when the event ends
+    pub ends_at_ms: u64,
+}
+
+/// Song Jukeula was playing when it was paused or replaced by external playback
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterruptedSong {
+    pub song: BasicSongInfo,
+    /// Where to restart it from
+    pub progress_ms: u32,
+}
+
 /// What Spotify is currently playing
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PlaybackStatus {
@@ -75,6 +393,26 @@ pub struct PlaybackStatus {
     pub state: PlaybackState,
     pub song: Option<BasicSongInfo>,
     pub progress_ms: Option<u32>,
+    /// Number of times the Spotify thread has been restarted after panicking
+    #[serde(default)]
+    pub restarts: u32,
+    /// Server time (ms since Unix epoch) when `progress_ms` was fetched. Whilst playing,
+    /// clients can extrapolate the current progress from this
+    pub sampled_at_ms: Option<u64>,
+    /// Spotify's own timestamp for when the playback state last changed
+    pub spotify_timestamp: Option<u64>,
+    /// Song waiting to be resumed, see `Client::resume_interrupted`
+    pub interrupted: Option<InterruptedSong>,
+    /// Volume of the device playing, 0 to 100
+    pub volume_percent: Option<u32>,
+    pub schedule: ScheduleState,
+    /// Server time (ms since Unix epoch) when an admin override of the schedule ends
+    pub schedule_override_until_ms: Option<u64>,
+    pub quiet_period: Option<QuietPeriod>,
+    /// Radio mode is on, playing recommendations when the list is empty
+    pub radio: bool,
+    /// Current song was picked by radio mode rather than requested
+    pub auto_picked: bool,
 }
 
 impl Default for PlaybackStatus {
@@ -83,10 +421,48 @@ impl Default for PlaybackStatus {
             state: PlaybackState::Unknown,
             song: None,
             progress_ms: None,
+            restarts: 0,
+            sampled_at_ms: None,
+            spotify_timestamp: None,
+            interrupted: None,
+            volume_percent: None,
+            schedule: ScheduleState::Unrestricted,
+            schedule_override_until_ms: None,
+            quiet_period: None,
+            radio: false,
+            auto_picked: false,
         }
     }
 }
 
+/// Server clock reading sent with status updates, so clients can correct `sampled_at_ms`
+/// for any difference with their own clock
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerTime {
+    pub now_ms: u64,
+}
+
+impl ServerTime {
+    pub fn now() -> ServerTime {
+        ServerTime {
+            now_ms: unix_time_ms(SystemTime::now()),
+        }
+    }
+}
+
+/// Liveness information published by the Spotify thread
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat {
+    /// When the Spotify thread last went around its loop
+    pub last_loop: Option<SystemTime>,
+    /// When the config reload thread last went around its loop
+    pub last_reload_loop: Option<SystemTime>,
+    /// When `Client::update_player_status` last succeeded
+    pub last_status_update: Option<SystemTime>,
+    /// Number of times the Spotify thread has been restarted after panicking
+    pub restarts: u32,
+}
+
 /// Summary of playlist info for syncing with web app
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PlaylistInfo {
@@ -94,26 +470,181 @@ pub struct PlaylistInfo {
 }
 
 /// Song ID to send over command-queue
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct SongRequestInfo {
     pub track_id: String,
+    /// Who is asking, see `ListEntry::requested_by`
+    pub requester: String,
+    /// See `ListEntry::client`
+    pub client: String,
+    pub tid: TaskID,
+}
+
+/// Client to look up quota for
+#[derive(Debug)]
+pub struct QuotaParams {
+    /// See `ListEntry::client`
+    pub client: String,
+    pub tid: TaskID,
+}
+
+/// Where an already requested song was found
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DuplicateLocation {
+    /// Waiting to be played
+    List,
+    /// Playing now
+    Playing,
+    /// Played recently
+    History,
+}
+
+/// Outcome of requesting a song
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RequestResult {
+    Added(BasicSongInfo),
+    /// Same song (possibly a different release of it) was already requested or played
+    Duplicate {
+        existing: BasicSongInfo,
+        location: DuplicateLocation,
+    },
+    /// Matches `playback.blocklist`
+    Blocked(BasicSongInfo),
+    /// Requester has reached one of the `quota` limits
+    OverQuota {
+        limit: QuotaLimit,
+        /// Explanation to show the requester
+        reason: String,
+        quota: QuotaInfo,
+    },
+}
+
+/// Which `quota` limit a request would go over
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum QuotaLimit {
+    PendingSongs,
+    PendingDuration,
+    PerHour,
+}
+
+/// Client's use of their `quota`. Remaining amounts are missing if there is no limit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaInfo {
+    /// See `ListEntry::client`
+    pub client: String,
+    /// Songs waiting in the list
+    pub pending_songs: u32,
+    /// Total length of songs waiting in the list
+    pub pending_ms: u64,
+    /// Songs added within the last hour
+    pub requests_last_hour: u32,
+    pub remaining_songs: Option<u32>,
+    pub remaining_ms: Option<u64>,
+    pub remaining_this_hour: Option<u32>,
+}
+
+/// Song started by Jukeula
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayedSong {
+    pub song: BasicSongInfo,
+    /// Milliseconds since Unix epoch when it started
+    pub played_at_ms: u64,
+    /// Picked by radio mode rather than requested
+    #[serde(default)]
+    pub auto_picked: bool,
+    #[serde(default)]
+    pub requested_by: Option<String>,
+    #[serde(default)]
+    pub features: Option<AudioInfo>,
+}
+
+/// What to search for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchKind {
+    Track,
+    Artist,
+    Album,
 }
 
 #[derive(Debug)]
 pub struct SearchParams {
-    pub title: String,
+    pub query: String,
+    pub kind: SearchKind,
     pub tid: TaskID,
+    /// Index of the first result, for fetching later pages
+    pub offset: u32,
+    /// Number of results, or `spotify.search_limit` if not given
+    pub limit: Option<u32>,
+    /// Only tracks by this artist, e.g after picking an artist from an artist search
+    pub artist: Option<String>,
+    /// Only tracks from this album
+    pub album: Option<String>,
+    /// Leave out tracks which cannot be played in the market searched
+    pub playable_only: bool,
+    pub hide_explicit: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// One page of search results. Only the list matching the `SearchKind` is filled in
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SearchResult {
     pub items: Vec<BasicSongInfo>,
+    pub artists: Vec<ArtistInfo>,
+    pub albums: Vec<AlbumInfo>,
+    pub offset: u32,
+    /// Offset of the next page, if there are more results
+    pub next_offset: Option<u32>,
+    /// Total number of matches, before any filtering
+    pub total: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Device {
-    name: String,
-    id: String,
+/// Search-as-you-type query
+#[derive(Debug)]
+pub struct SuggestParams {
+    pub prefix: String,
+    /// Identifies the web client, so its older queries can be dropped when it sends a new one
+    pub client: String,
+    pub tid: TaskID,
+}
+
+/// Songs and artists matching the start of a query
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SuggestResult {
+    pub prefix: String,
+    /// Songs from history first, then from Spotify
+    pub tracks: Vec<BasicSongInfo>,
+    pub artists: Vec<ArtistInfo>,
+    /// The client sent a newer query before this one was looked up, so nothing was
+    pub superseded: bool,
+}
+
+impl SuggestResult {
+    pub fn superseded(prefix: &str) -> SuggestResult {
+        SuggestResult {
+            prefix: prefix.to_string(),
+            superseded: true,
+            ..SuggestResult::default()
+        }
+    }
+}
+
+/// Artist or album to list the contents of
+#[derive(Debug, Clone, PartialEq)]
+pub enum BrowseKind {
+    /// Artist's most popular tracks, by Spotify ID
+    ArtistTopTracks(String),
+    ArtistAlbums(String),
+    AlbumTracks(String),
+}
+
+#[derive(Debug)]
+pub struct BrowseParams {
+    pub kind: BrowseKind,
+    pub tid: TaskID,
+    /// Index of the first result. Top tracks are always a single page
+    pub offset: u32,
+    pub limit: Option<u32>,
+    pub playable_only: bool,
+    pub hide_explicit: bool,
 }
 
 #[derive(Debug)]
@@ -131,17 +662,35 @@ pub struct DeviceListResult {
 pub enum SpotifyCommand {
     Request(SongRequestInfo),
     Search(SearchParams),
+    Browse(BrowseParams),
+    Suggest(SuggestParams),
+    Quota(QuotaParams),
     SetAuthToken(TokenInfo),
     ClearAuth,
     ListDevices(DeviceListParams),
     SetActiveDevice(String),
     ClearDevice,
+    /// Vote from the given client ID to skip the current song
+    VoteSkip(String),
+    Pause,
+    Resume,
+    SetVolume(u8),
+    /// Jump to position in current song, in milliseconds
+    Seek(u32),
+    /// Ignore schedule for given number of minutes, or stop ignoring it if zero
+    OverrideSchedule(u32),
+    /// Turn radio mode on or off, until the config setting next changes
+    SetRadio(bool),
+    ReloadConfig(Box<Config>),
 }
 
 /// Types of things a Spotify thread can respond to a command with
 #[derive(Debug, Serialize)]
 pub enum CommandResponseDataType {
     Search(SearchResult),
+    Suggest(SuggestResult),
+    Request(RequestResult),
+    Quota(QuotaInfo),
     DeviceList(DeviceListResult),
     Error(String),
 }
@@ -154,7 +703,140 @@ pub struct CommandResponse {
 }
 
 /// Identifier for a task, used to match up return values
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub struct TaskID {
     pub id: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(uri: &str, artist: &str, title: &str, isrc: Option<&str>) -> BasicSongInfo {
+        BasicSongInfo {
+            spotify_uri: uri.into(),
+            title: title.into(),
+            artists: vec![ArtistInfo {
+                id: normalize_name(artist),
+                name: artist.into(),
+            }],
+            album: AlbumInfo::default(),
+            duration_ms: 180_000,
+            isrc: isrc.map(String::from),
+            explicit: false,
+            popularity: 50,
+            is_playable: None,
+        }
+    }
+
+    #[test]
+    fn normalize_title_removes_release_markers() {
+        assert_eq!(normalize_title("Song (2011 Remaster)"), "song");
+        assert_eq!(normalize_title("Song - Remastered 2009"), "song");
+        assert_eq!(normalize_title("Song [Mono]"), "song");
+        assert_eq!(normalize_title("Song (Single Version)"), "song");
+        assert_eq!(normalize_title("Song - Radio Edit"), "song");
+        assert_eq!(normalize_title("Song (Explicit) [Deluxe Edition]"), "song");
+    }
+
+    #[test]
+    fn normalize_title_keeps_other_brackets() {
+        assert_eq!(
+            normalize_title("Song (feat. Stereolab)"),
+            "song feat stereolab"
+        );
+        assert_eq!(normalize_title("Song (Monolith Mix)"), "song monolith mix");
+        assert_eq!(
+            normalize_title("Song (Cleaner Version)"),
+            "song cleaner version"
+        );
+        assert_eq!(
+            normalize_title("Song - Live at Wembley"),
+            "song live at wembley"
+        );
+        assert_eq!(normalize_title("Monochrome"), "monochrome");
+    }
+
+    #[test]
+    fn same_song_across_releases() {
+        let a = song("spotify:track:1", "Artist", "Song", None);
+        let b = song("spotify:track:2", "artist", "Song - 2011 Remaster", None);
+        assert!(a.is_same_song(&b));
+
+        let c = song(
+            "spotify:track:3",
+            "Someone Else",
+            "Another",
+            Some("GBAAA0000001"),
+        );
+        let d = song(
+            "spotify:track:4",
+            "Other Name",
+            "Other Title",
+            Some("gbaaa0000001"),
+        );
+        assert!(c.is_same_song(&d));
+    }
+
+    #[test]
+    fn different_songs() {
+        let a = song("spotify:track:1", "Artist", "Song", None);
+        assert!(!a.is_same_song(&song(
+            "spotify:track:2",
+            "Artist",
+            "Song (Monolith Mix)",
+            None
+        )));
+        assert!(!a.is_same_song(&song(
+            "spotify:track:3",
+            "Artist",
+            "Song (feat. Stereolab)",
+            None
+        )));
+        assert!(!a.is_same_song(&song("spotify:track:4", "Other Artist", "Song", None)));
+    }
+
+    #[test]
+    fn different_isrc_falls_back_to_names() {
+        // Remasters are often given a new ISRC, so the names still decide
+        let a = song("spotify:track:1", "Artist", "Song", Some("GBAAA0000001"));
+        let b = song(
+            "spotify:track:2",
+            "Artist",
+            "Song - Remastered",
+            Some("GBAAA0000002"),
+        );
+        assert!(a.is_same_song(&b));
+        let c = song(
+            "spotify:track:3",
+            "Artist",
+            "Other Song",
+            Some("GBAAA0000003"),
+        );
+        assert!(!a.is_same_song(&c));
+    }
+
+    fn audio(tempo: f32, energy: f32) -> AudioInfo {
+        AudioInfo {
+            tempo,
+            energy,
+            valence: 0.5,
+            key: 0,
+        }
+    }
+
+    #[test]
+    fn distance_combines_energy_and_tempo() {
+        let a = audio(120.0, 0.5);
+        assert_eq!(a.distance(&a), 0.0);
+        assert_eq!(a.distance(&audio(120.0, 0.75)), 0.25);
+        assert_eq!(a.distance(&audio(70.0, 0.5)), 0.5);
+        assert_eq!(a.distance(&audio(170.0, 0.25)), 0.75);
+    }
+
+    #[test]
+    fn distance_is_symmetric() {
+        let (a, b) = (audio(90.0, 0.2), audio(140.0, 0.9));
+        assert_eq!(a.distance(&b), b.distance(&a));
+    }
+}
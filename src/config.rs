@@ -0,0 +1,1045 @@
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use failure::{format_err, Error};
+use rspotify::spotify::senum::Country;
+use serde_derive::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+/// Config file used if `--config` is not specified, if it exists
+static DEFAULT_CONFIG_PATH: &str = "juke.toml";
+
+/// OAuth scopes which Jukeula cannot function without
+static REQUIRED_SCOPES: &[&str] = &["user-read-playback-state", "user-modify-playback-state"];
+
+/// Settings which are only read at startup, so cannot be changed by reloading
+static RESTART_REQUIRED: &[&str] = &["log", "state_file", "device_file", "timezone", "web"];
+
+/// Command line arguments. Anything specified here overrides the config file
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "juke", about = "Collaborative jukebox backed by Spotify")]
+pub struct Opt {
+    /// Path to TOML config file [default: juke.toml, if it exists]
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Address to bind web server to
+    #[structopt(long)]
+    pub host: Option<String>,
+
+    /// Port for web server (also settable via $PORT)
+    #[structopt(short, long)]
+    pub port: Option<u32>,
+
+    /// Log filter, in `env_logger` syntax e.g "juke=debug"
+    #[structopt(long)]
+    pub log: Option<String>,
+
+    /// File to persist the song list into between restarts
+    #[structopt(long, parse(from_os_str))]
+    pub state_file: Option<PathBuf>,
+}
+
+/// Web server settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    /// Bind address, e.g 0.0.0.0
+    pub host: String,
+    pub port: u32,
+    /// Tell clients apart by the `X-Forwarded-For` header, as set by a reverse proxy in front
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            host: "0.0.0.0".to_string(),
+            port: 8081,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+/// Spotify API usage
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpotifyConfig {
+    /// OAuth scopes requested when authenticating
+    pub scopes: Vec<String>,
+    /// How often to refresh the auth token (which expires after an hour)
+    pub token_refresh_interval_ms: u32,
+    /// Number of results returned by searches, unless the request asks for a different number
+    pub search_limit: u32,
+    /// Country code searches are limited to, e.g "GB". If not set, the country of the user's
+    /// account is used (which needs the `user-read-private` scope)
+    pub market: Option<String>,
+}
+
+impl SpotifyConfig {
+    /// Scopes as space-separated string, as used by `SpotifyOAuth::scope`
+    pub fn scope_string(&self) -> String {
+        self.scopes.join(" ")
+    }
+}
+
+impl Default for SpotifyConfig {
+    fn default() -> Self {
+        SpotifyConfig {
+            scopes: REQUIRED_SCOPES.iter().map(|s| s.to_string()).collect(),
+            token_refresh_interval_ms: 1000 * 60 * 5,
+            search_limit: 40,
+            market: None,
+        }
+    }
+}
+
+/// How often the playback status is checked. Mid-song the end of the song is predicted,
+/// so only needs checked occasionally
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    /// Shortest interval, used close to the predicted end of a song
+    pub min_ms: u32,
+    /// Longest interval while a song is playing
+    pub max_ms: u32,
+    /// How long before the predicted end of a song to start polling at `min_ms`
+    pub end_window_ms: u32,
+    /// Interval while paused, or without a device etc
+    pub paused_ms: u32,
+    /// Interval once nothing has played for `idle_after_ms`
+    pub idle_ms: u32,
+    pub idle_after_ms: u32,
+}
+
+impl PollingConfig {
+    /// Longest time between status checks in any situation
+    pub fn longest_ms(&self) -> u32 {
+        *[self.min_ms, self.max_ms, self.paused_ms, self.idle_ms]
+            .iter()
+            .max()
+            .unwrap()
+    }
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            min_ms: 500,
+            max_ms: 15_000,
+            end_window_ms: 5000,
+            paused_ms: 5000,
+            idle_ms: 30_000,
+            idle_after_ms: 1000 * 60 * 10,
+        }
+    }
+}
+
+/// What to do when something Jukeula did not start is playing, e.g a playlist started directly in Spotify
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalPolicy {
+    /// Leave it alone until playback stops
+    Wait,
+    /// Play the next requested song once the current track finishes
+    AfterTrack,
+    /// Replace it with the next requested song straight away
+    Immediately,
+}
+
+/// Rules for what gets played
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    /// Number of different clients which must vote to skip the current song
+    pub skip_threshold: u32,
+    /// Requests with a title containing any of these (case insensitive) are ignored
+    pub blocklist: Vec<String>,
+    /// Hand the next song to Spotify's own play queue shortly before the current one ends,
+    /// avoiding the gap while waiting for playback to stop
+    pub gapless: bool,
+    /// How long before the end of the current song to queue the next, when `gapless` is enabled
+    pub prequeue_ms: u32,
+    /// Whether requested songs take over from external playback
+    pub external: ExternalPolicy,
+    /// Restart an interrupted song where it left off, before playing anything else from the list
+    pub auto_resume: bool,
+    /// Number of recently played songs remembered, e.g to reject requests for songs just played
+    pub history_size: usize,
+    /// How the next song is chosen from the list
+    pub order: ListOrder,
+    /// Avoid songs by the same artist as any of the last this many songs, if there are others
+    /// to choose from. Zero to allow
+    pub avoid_same_artist: usize,
+    /// Avoid songs from whoever requested the last song, if there are others to choose from
+    pub avoid_same_requester: bool,
+    /// With `order = "smooth"`, requests waiting longer than this are played first, oldest first
+    pub max_wait_minutes: u32,
+}
+
+impl PlaybackConfig {
+    /// Check if title matches anything in the blocklist
+    pub fn is_blocked(&self, title: &str) -> bool {
+        let title = title.to_lowercase();
+        self.blocklist
+            .iter()
+            .any(|b| title.contains(&b.to_lowercase()))
+    }
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            skip_threshold: 1,
+            blocklist: vec!["scatman".to_string(), "freestyler".to_string()],
+            gapless: false,
+            prequeue_ms: 5000,
+            external: ExternalPolicy::Wait,
+            auto_resume: true,
+            history_size: 50,
+            order: ListOrder::Random,
+            avoid_same_artist: 2,
+            avoid_same_requester: true,
+            max_wait_minutes: 30,
+        }
+    }
+}
+
+/// Names shown in the web interface
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrandingConfig {
+    /// Short name, shown in the navigation bar
+    pub name: String,
+    /// Page title
+    pub title: String,
+}
+
+impl Default for BrandingConfig {
+    fn default() -> Self {
+        BrandingConfig {
+            name: "Count Jukeula".to_string(),
+            title: "Count Jukeula the Chune Maker".to_string(),
+        }
+    }
+}
+
+/// Volume to switch to during part of the day
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeProfile {
+    /// Local time the profile starts, as "HH:MM"
+    pub from: String,
+    /// Local time the profile ends, as "HH:MM". May be earlier than `from` to span midnight
+    pub to: String,
+    /// Volume, 0 to 100
+    pub percent: u8,
+}
+
+/// Parse "HH:MM" pair of times
+fn parse_times(from: &str, to: &str) -> Result<(NaiveTime, NaiveTime), Error> {
+    let parse = |s: &str| {
+        NaiveTime::parse_from_str(s, "%H:%M")
+            .map_err(|_| format_err!("invalid time {:?}, expected HH:MM", s))
+    };
+    Ok((parse(from)?, parse(to)?))
+}
+
+impl VolumeProfile {
+    /// Parse `from` and `to`
+    fn times(&self) -> Result<(NaiveTime, NaiveTime), Error> {
+        parse_times(&self.from, &self.to)
+    }
+
+    /// If given time falls within this profile
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        match self.times() {
+            Ok((from, to)) if from <= to => from <= t && t < to,
+            Ok((from, to)) => t >= from || t < to,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Automatic volume changes
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeConfig {
+    /// Applied when each starts. If profiles overlap, the first listed wins
+    pub profiles: Vec<VolumeProfile>,
+    /// Volume, 0 to 100, restored when a profile ends and no other starts. If unset, the
+    /// volume stays as the last profile left it
+    pub default_percent: Option<u8>,
+}
+
+impl VolumeConfig {
+    /// Profile which applies at given local time
+    pub fn active_profile(&self, t: NaiveTime) -> Option<&VolumeProfile> {
+        self.profiles.iter().find(|p| p.contains(t))
+    }
+}
+
+/// Hours on certain days of the week
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleRule {
+    /// Day names, e.g "mon" or "monday"
+    pub days: Vec<String>,
+    /// Local time, as "HH:MM"
+    pub from: String,
+    /// Local time, as "HH:MM". If earlier than `from` the hours continue past midnight, into the next day
+    pub to: String,
+}
+
+impl ScheduleRule {
+    /// Parse `days`
+    fn weekdays(&self) -> Result<Vec<Weekday>, Error> {
+        self.days
+            .iter()
+            .map(|d| {
+                d.parse::<Weekday>()
+                    .map_err(|_| format_err!("invalid day {:?}, expected e.g \"mon\"", d))
+            })
+            .collect()
+    }
+
+    /// If given local day and time fall within these hours
+    pub fn contains(&self, day: Weekday, t: NaiveTime) -> bool {
+        let (days, (from, to)) = match (self.weekdays(), parse_times(&self.from, &self.to)) {
+            (Ok(d), Ok(t)) => (d, t),
+            _ => return false,
+        };
+        if from <= to {
+            days.contains(&day) && from <= t && t < to
+        } else {
+            (days.contains(&day) && t >= from) || (days.contains(&day.pred()) && t < to)
+        }
+    }
+}
+
+/// Weekly hours when songs from the list are played
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Hours when songs are played. If empty there is no restriction
+    pub hours: Vec<ScheduleRule>,
+}
+
+impl ScheduleConfig {
+    /// If any rule allows playing at given local day and time
+    pub fn is_open(&self, day: Weekday, t: NaiveTime) -> bool {
+        self.hours.iter().any(|r| r.contains(day, t))
+    }
+}
+
+/// What happens during a quiet period
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuietAction {
+    /// Pause, resuming afterwards
+    Pause,
+    /// Keep playing at `volume_percent`, restoring the volume afterwards
+    Volume,
+}
+
+/// Quiet periods from calendar events, e.g meetings in a room sharing the speakers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuietConfig {
+    /// `.ics` files to read events from
+    pub calendars: Vec<PathBuf>,
+    /// How often the calendar files are read again
+    pub refresh_ms: u32,
+    /// Events with a summary containing any of these (case insensitive) are quiet periods.
+    /// If empty, every event is
+    pub filter: Vec<String>,
+    pub action: QuietAction,
+    /// Volume during quiet periods, when `action` is "volume"
+    pub volume_percent: u8,
+}
+
+impl QuietConfig {
+    /// Check if event summary matches `filter`
+    pub fn matches(&self, summary: &str) -> bool {
+        let summary = summary.to_lowercase();
+        self.filter.is_empty()
+            || self
+                .filter
+                .iter()
+                .any(|f| summary.contains(&f.to_lowercase()))
+    }
+}
+
+impl Default for QuietConfig {
+    fn default() -> Self {
+        QuietConfig {
+            calendars: vec![],
+            refresh_ms: 1000 * 60 * 5,
+            filter: vec![],
+            action: QuietAction::Pause,
+            volume_percent: 20,
+        }
+    }
+}
+
+/// How the next song is chosen from the list
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListOrder {
+    Random,
+    /// Closest in energy and tempo to the last song, unless a request has waited longer than
+    /// `playback.max_wait_minutes`
+    Smooth,
+}
+
+/// Radio mode, playing songs like recent requests when the list is empty
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RadioConfig {
+    /// Can also be changed by admins without editing the config
+    pub enabled: bool,
+    /// Number of recently requested songs used to find similar songs
+    pub seed_tracks: usize,
+    /// Number of artists of recently requested songs used to find similar songs
+    pub seed_artists: usize,
+    /// Songs played within this many minutes are not picked again (going back as far as
+    /// `playback.history_size` songs)
+    pub cooldown_minutes: u32,
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        RadioConfig {
+            enabled: false,
+            seed_tracks: 3,
+            seed_artists: 2,
+            cooldown_minutes: 120,
+        }
+    }
+}
+
+/// Limits on how much each client can add to the list. Zero for no limit
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Songs each requester can have waiting in the list
+    pub max_pending: u32,
+    /// Total length of songs each requester can have waiting in the list, in minutes
+    pub max_pending_minutes: u32,
+    /// Songs each requester can add in any hour
+    pub max_per_hour: u32,
+}
+
+/// Search-as-you-type suggestions, from `/api/suggest`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SuggestConfig {
+    /// Maximum number of songs suggested
+    pub tracks: u32,
+    /// Maximum number of artists suggested
+    pub artists: u32,
+    /// Queries shorter than this get no suggestions
+    pub min_length: usize,
+    /// How long to wait for the next keystroke before looking up a query
+    pub debounce_ms: u32,
+}
+
+impl Default for SuggestConfig {
+    fn default() -> Self {
+        SuggestConfig {
+            tracks: 5,
+            artists: 3,
+            min_length: 2,
+            debounce_ms: 250,
+        }
+    }
+}
+
+/// Caching of Spotify search results and track details
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Pages of search (and artist/album) results kept. Zero disables the cache
+    pub search_entries: usize,
+    /// Tracks kept, from search results or requests. Zero disables the cache
+    pub track_entries: usize,
+    /// How long entries are kept for
+    pub ttl_ms: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            search_entries: 200,
+            track_entries: 2000,
+            ttl_ms: 1000 * 60 * 30,
+        }
+    }
+}
+
+/// Access to admin actions, like pausing for an announcement
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Secret given in the `X-Admin-Token` header or `admin_token` parameter. Admin actions are
+    /// disabled if unset
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Check given token against the configured one
+    pub fn is_admin(&self, token: Option<&str>) -> bool {
+        match (&self.token, token) {
+            (Some(expected), Some(t)) => expected == t,
+            _ => false,
+        }
+    }
+}
+
+/// A setting which differs between two configs
+#[derive(Debug, PartialEq)]
+pub struct ConfigChange {
+    /// Dotted path to setting, e.g "playback.skip_threshold"
+    pub key: String,
+    /// If the setting is only read at startup
+    pub needs_restart: bool,
+}
+
+/// Recursively compare two TOML values, collecting the dotted keys which differ
+fn diff_values(
+    prefix: &str,
+    old: Option<&toml::Value>,
+    new: Option<&toml::Value>,
+    out: &mut Vec<String>,
+) {
+    match (
+        old.and_then(|v| v.as_table()),
+        new.and_then(|v| v.as_table()),
+    ) {
+        (Some(a), Some(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for k in keys {
+                let path = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                diff_values(&path, a.get(k), b.get(k), out);
+            }
+        }
+        _ => {
+            if old != new {
+                out.push(prefix.to_string());
+            }
+        }
+    }
+}
+
+/// App configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Log filter, in `env_logger` syntax
+    pub log: String,
+    /// Where `TheList` is saved on exit and loaded from on startup
+    pub state_file: Option<PathBuf>,
+    /// Where the chosen playback device is saved, so it can be selected again after restarting
+    pub device_file: Option<PathBuf>,
+    /// Time zone name for `schedule` and `volume` times and `quiet` calendar events, e.g
+    /// "Europe/London". Defaults to the system time zone
+    pub timezone: Option<String>,
+    pub web: WebConfig,
+    pub spotify: SpotifyConfig,
+    pub polling: PollingConfig,
+    pub playback: PlaybackConfig,
+    pub branding: BrandingConfig,
+    pub admin: AdminConfig,
+    pub volume: VolumeConfig,
+    pub schedule: ScheduleConfig,
+    pub quiet: QuietConfig,
+    pub cache: CacheConfig,
+    pub suggest: SuggestConfig,
+    pub radio: RadioConfig,
+    pub quota: QuotaConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log: "juke=debug".to_string(),
+            state_file: None,
+            device_file: None,
+            timezone: None,
+            web: WebConfig::default(),
+            spotify: SpotifyConfig::default(),
+            polling: PollingConfig::default(),
+            playback: PlaybackConfig::default(),
+            branding: BrandingConfig::default(),
+            admin: AdminConfig::default(),
+            volume: VolumeConfig::default(),
+            schedule: ScheduleConfig::default(),
+            quiet: QuietConfig::default(),
+            cache: CacheConfig::default(),
+            suggest: SuggestConfig::default(),
+            radio: RadioConfig::default(),
+            quota: QuotaConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Parse config file
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("Could not read config file {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| format_err!("Could not parse config file {}: {}", path.display(), e))
+    }
+
+    /// Build config from file (if any), environment and command line arguments, then validate it
+    pub fn load(opt: &Opt) -> Result<Config, Error> {
+        let mut cfg = match &opt.config {
+            Some(p) => Config::from_file(p)?,
+            None => {
+                let p = Path::new(DEFAULT_CONFIG_PATH);
+                if p.exists() {
+                    Config::from_file(p)?
+                } else {
+                    Config::default()
+                }
+            }
+        };
+
+        // $PORT is set by Heroku-style deployments (see Procfile)
+        if let Ok(p) = std::env::var("PORT") {
+            cfg.web.port = p
+                .parse::<u32>()
+                .map_err(|_| format_err!("Malformed $PORT value {:?}", p))?;
+        }
+
+        if let Some(h) = &opt.host {
+            cfg.web.host = h.clone();
+        }
+        if let Some(p) = opt.port {
+            cfg.web.port = p;
+        }
+        if let Some(l) = &opt.log {
+            cfg.log = l.clone();
+        }
+        if let Some(s) = &opt.state_file {
+            cfg.state_file = Some(s.clone());
+        }
+
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// List settings which differ in `new`, and whether they need a restart to apply
+    pub fn changes(&self, new: &Config) -> Vec<ConfigChange> {
+        let old = toml::Value::try_from(self).expect("Config is always serialisable");
+        let new = toml::Value::try_from(new).expect("Config is always serialisable");
+        let mut keys = vec![];
+        diff_values("", Some(&old), Some(&new), &mut keys);
+        keys.into_iter()
+            .map(|key| ConfigChange {
+                needs_restart: RESTART_REQUIRED
+                    .iter()
+                    .any(|r| key == *r || key.starts_with(&format!("{}.", r))),
+                key,
+            })
+            .collect()
+    }
+
+    /// Take reloadable settings from `new`, keeping the current value of anything which needs a restart
+    pub fn reloaded(&self, new: &Config) -> Config {
+        Config {
+            log: self.log.clone(),
+            state_file: self.state_file.clone(),
+            device_file: self.device_file.clone(),
+            timezone: self.timezone.clone(),
+            web: self.web.clone(),
+            ..new.clone()
+        }
+    }
+
+    /// Current local date and time, in `timezone` if set or else the system time zone
+    pub fn local_now(&self) -> NaiveDateTime {
+        match self.zone() {
+            Some(tz) => Utc::now().with_timezone(&tz).naive_local(),
+            None => Local::now().naive_local(),
+        }
+    }
+
+    /// Configured `timezone`, or `None` to use the system time zone
+    pub fn zone(&self) -> Option<Tz> {
+        self.timezone.as_ref().and_then(|tz| tz.parse().ok())
+    }
+
+    /// Check values are sensible, reporting all problems at once
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems: Vec<String> = vec![];
+
+        if self.web.port == 0 || self.web.port > 65535 {
+            problems.push(format!(
+                "web.port must be between 1 and 65535 (got {})",
+                self.web.port
+            ));
+        } else if (self.web.host.as_str(), self.web.port as u16)
+            .to_socket_addrs()
+            .is_err()
+        {
+            problems.push(format!(
+                "web.host {:?} is not a valid address to bind to",
+                self.web.host
+            ));
+        }
+
+        if self.log.trim().is_empty() {
+            problems.push("log filter must not be empty (e.g \"juke=debug\")".into());
+        }
+
+        if self.spotify.token_refresh_interval_ms == 0
+            || self.spotify.token_refresh_interval_ms > 1000 * 60 * 60
+        {
+            problems.push(format!(
+                "spotify.token_refresh_interval_ms must be between 1 and 3600000, as tokens expire after an hour (got {})",
+                self.spotify.token_refresh_interval_ms
+            ));
+        }
+        if self.spotify.search_limit == 0 || self.spotify.search_limit > 50 {
+            problems.push(format!(
+                "spotify.search_limit must be between 1 and 50 (got {})",
+                self.spotify.search_limit
+            ));
+        }
+        if let Some(m) = &self.spotify.market {
+            if Country::from_str(m).is_none() {
+                problems.push(format!(
+                    "spotify.market must be a two letter country code (got {:?})",
+                    m
+                ));
+            }
+        }
+        if self.polling.min_ms < 100 {
+            problems.push(format!(
+                "polling.min_ms must be at least 100 (got {})",
+                self.polling.min_ms
+            ));
+        }
+        for (name, value) in &[
+            ("max_ms", self.polling.max_ms),
+            ("end_window_ms", self.polling.end_window_ms),
+            ("paused_ms", self.polling.paused_ms),
+            ("idle_ms", self.polling.idle_ms),
+        ] {
+            if *value < self.polling.min_ms {
+                problems.push(format!(
+                    "polling.{} must be at least polling.min_ms (got {} and {})",
+                    name, value, self.polling.min_ms
+                ));
+            }
+        }
+
+        for required in REQUIRED_SCOPES {
+            if !self.spotify.scopes.iter().any(|s| s == required) {
+                problems.push(format!("spotify.scopes must include {:?}", required));
+            }
+        }
+
+        if self.playback.skip_threshold == 0 {
+            problems.push("playback.skip_threshold must be at least 1".into());
+        }
+        if self.playback.blocklist.iter().any(|b| b.trim().is_empty()) {
+            problems.push("playback.blocklist must not contain empty entries".into());
+        }
+
+        for (key, file) in &[
+            ("state_file", &self.state_file),
+            ("device_file", &self.device_file),
+        ] {
+            if let Some(f) = file {
+                let parent = f.parent().unwrap_or_else(|| Path::new(""));
+                if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                    problems.push(format!(
+                        "{} directory {} does not exist",
+                        key,
+                        parent.display()
+                    ));
+                }
+            }
+        }
+
+        if self.playback.gapless
+            && (self.playback.prequeue_ms <= self.polling.min_ms
+                || self.playback.prequeue_ms > self.polling.end_window_ms)
+        {
+            problems.push(format!(
+                "playback.prequeue_ms must be longer than polling.min_ms, and no longer than polling.end_window_ms (got {}, {} and {})",
+                self.playback.prequeue_ms, self.polling.min_ms, self.polling.end_window_ms
+            ));
+        }
+
+        if self.branding.name.trim().is_empty() {
+            problems.push("branding.name must not be empty".into());
+        }
+        for (i, p) in self.volume.profiles.iter().enumerate() {
+            match p.times() {
+                Ok((from, to)) if from == to => problems.push(format!(
+                    "volume.profiles[{}] must not start and end at the same time",
+                    i
+                )),
+                Ok(_) => (),
+                Err(e) => problems.push(format!("volume.profiles[{}]: {}", i, e)),
+            }
+            if p.percent > 100 {
+                problems.push(format!(
+                    "volume.profiles[{}].percent must be 0 to 100 (got {})",
+                    i, p.percent
+                ));
+            }
+        }
+        if let Some(p) = self.volume.default_percent {
+            if p > 100 {
+                problems.push(format!(
+                    "volume.default_percent must be 0 to 100 (got {})",
+                    p
+                ));
+            }
+        }
+        for (i, r) in self.schedule.hours.iter().enumerate() {
+            if let Err(e) = r.weekdays() {
+                problems.push(format!("schedule.hours[{}]: {}", i, e));
+            }
+            match parse_times(&r.from, &r.to) {
+                Ok((from, to)) if from == to => problems.push(format!(
+                    "schedule.hours[{}] must not start and end at the same time",
+                    i
+                )),
+                Ok(_) => (),
+                Err(e) => problems.push(format!("schedule.hours[{}]: {}", i, e)),
+            }
+        }
+        for c in &self.quiet.calendars {
+            if !c.is_file() {
+                problems.push(format!("quiet.calendars file {} not found", c.display()));
+            }
+        }
+        if self.quiet.refresh_ms < 1000 {
+            problems.push(format!(
+                "quiet.refresh_ms must be at least 1000 (got {})",
+                self.quiet.refresh_ms
+            ));
+        }
+        let seeds = self.radio.seed_tracks + self.radio.seed_artists;
+        if seeds == 0 || seeds > 5 {
+            problems.push(format!(
+                "radio.seed_tracks plus radio.seed_artists must be between 1 and 5 (got {})",
+                seeds
+            ));
+        }
+        if self.suggest.tracks > 50 || self.suggest.artists > 50 {
+            problems.push(format!(
+                "suggest.tracks and suggest.artists must be at most 50 (got {} and {})",
+                self.suggest.tracks, self.suggest.artists
+            ));
+        }
+        if self.quiet.volume_percent > 100 {
+            problems.push(format!(
+                "quiet.volume_percent must be 0 to 100 (got {})",
+                self.quiet.volume_percent
+            ));
+        }
+        if let Some(tz) = &self.timezone {
+            if tz.parse::<Tz>().is_err() {
+                problems.push(format!(
+                    "timezone {:?} is not a known time zone, expected e.g \"Europe/London\"",
+                    tz
+                ));
+            }
+        }
+        if self.admin.token.as_ref().map(|t| t.trim().is_empty()) == Some(true) {
+            problems
+                .push("admin.token must not be empty, remove it to disable admin actions".into());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!("{}", problems.join("\n")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    fn rule(days: &[&str], from: &str, to: &str) -> ScheduleRule {
+        ScheduleRule {
+            days: days.iter().map(|d| d.to_string()).collect(),
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+
+    fn profile(from: &str, to: &str, percent: u8) -> VolumeProfile {
+        VolumeProfile {
+            from: from.into(),
+            to: to.into(),
+            percent,
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_reports_all_problems() {
+        let mut cfg = Config::default();
+        cfg.web.port = 0;
+        cfg.log = " ".into();
+        cfg.playback.skip_threshold = 0;
+        cfg.playback.blocklist = vec!["".into()];
+        cfg.volume.profiles = vec![profile("09:00", "09:00", 150), profile("9am", "17:00", 50)];
+        cfg.volume.default_percent = Some(101);
+        cfg.schedule.hours = vec![rule(&["mon", "someday"], "08:00", "25:00")];
+        cfg.timezone = Some("../etc/passwd".into());
+        let err = cfg.validate().unwrap_err().to_string();
+        for expected in &[
+            "web.port",
+            "log filter",
+            "playback.skip_threshold",
+            "playback.blocklist",
+            "volume.profiles[0] must not start and end at the same time",
+            "volume.profiles[0].percent",
+            "volume.profiles[1]: invalid time \"9am\"",
+            "volume.default_percent",
+            "schedule.hours[0]: invalid day \"someday\"",
+            "schedule.hours[0]: invalid time \"25:00\"",
+            "timezone",
+        ] {
+            assert!(err.contains(expected), "{:?} not in {:?}", expected, err);
+        }
+    }
+
+    #[test]
+    fn no_changes() {
+        let cfg = Config::default();
+        assert_eq!(cfg.changes(&cfg.clone()), vec![]);
+    }
+
+    #[test]
+    fn changes_note_restarts() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.playback.skip_threshold += 1;
+        new.web.port += 1;
+        new.log = "juke=trace".into();
+        new.timezone = Some("Europe/London".into());
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                ConfigChange {
+                    key: "log".into(),
+                    needs_restart: true,
+                },
+                ConfigChange {
+                    key: "playback.skip_threshold".into(),
+                    needs_restart: false,
+                },
+                ConfigChange {
+                    key: "timezone".into(),
+                    needs_restart: true,
+                },
+                ConfigChange {
+                    key: "web.port".into(),
+                    needs_restart: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reloaded_keeps_restart_settings() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.playback.skip_threshold += 1;
+        new.web.port += 1;
+        let cfg = old.reloaded(&new);
+        assert_eq!(cfg.playback.skip_threshold, new.playback.skip_threshold);
+        assert_eq!(cfg.web.port, old.web.port);
+    }
+
+    #[test]
+    fn volume_profiles() {
+        let v = VolumeConfig {
+            profiles: vec![profile("22:00", "07:00", 20), profile("06:00", "09:00", 50)],
+            default_percent: None,
+        };
+        assert_eq!(v.active_profile(time("23:00")).map(|p| p.percent), Some(20));
+        // Overlapping, so the first listed wins
+        assert_eq!(v.active_profile(time("06:30")).map(|p| p.percent), Some(20));
+        assert_eq!(v.active_profile(time("07:00")).map(|p| p.percent), Some(50));
+        assert_eq!(v.active_profile(time("12:00")), None);
+    }
+
+    #[test]
+    fn schedule_hours() {
+        let s = ScheduleConfig {
+            hours: vec![rule(&["mon", "tuesday"], "08:30", "18:00")],
+        };
+        assert!(!s.is_open(Weekday::Mon, time("08:29")));
+        assert!(s.is_open(Weekday::Mon, time("08:30")));
+        assert!(s.is_open(Weekday::Tue, time("17:59")));
+        assert!(!s.is_open(Weekday::Tue, time("18:00")));
+        assert!(!s.is_open(Weekday::Wed, time("12:00")));
+    }
+
+    #[test]
+    fn schedule_hours_past_midnight() {
+        let s = ScheduleConfig {
+            hours: vec![rule(&["fri"], "22:00", "02:00")],
+        };
+        assert!(!s.is_open(Weekday::Fri, time("21:59")));
+        assert!(s.is_open(Weekday::Fri, time("23:00")));
+        // Continues into Saturday morning, but not Friday morning
+        assert!(s.is_open(Weekday::Sat, time("01:59")));
+        assert!(!s.is_open(Weekday::Sat, time("02:00")));
+        assert!(!s.is_open(Weekday::Fri, time("01:00")));
+        assert!(!s.is_open(Weekday::Sat, time("23:00")));
+    }
+
+    #[test]
+    fn schedule_hours_any_rule() {
+        let s = ScheduleConfig {
+            hours: vec![
+                rule(&["sat"], "10:00", "12:00"),
+                rule(&["sun"], "14:00", "16:00"),
+            ],
+        };
+        assert!(s.is_open(Weekday::Sat, time("11:00")));
+        assert!(s.is_open(Weekday::Sun, time("15:00")));
+        assert!(!s.is_open(Weekday::Sun, time("11:00")));
+    }
+
+    #[test]
+    fn local_now_in_timezone() {
+        let cfg = Config {
+            timezone: Some("Asia/Tokyo".into()),
+            ..Config::default()
+        };
+        // Tokyo has no daylight saving, so is always 9 hours ahead
+        let offset = cfg.local_now() - Utc::now().naive_utc();
+        assert!((offset.num_seconds() - 9 * 3600).abs() < 60, "{:?}", offset);
+    }
+}
@@ -1,33 +1,49 @@
-use log::{info, trace, warn};
+use log::{error, info, trace, warn};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use failure::Error;
+use structopt::StructOpt;
 
+mod cache;
+mod calendar;
 mod client;
 mod commands;
 mod common;
+mod config;
+mod metrics;
+mod spotify_ext;
 mod web;
 
 use crate::client::{Client, TheList};
 use crate::commands::{LockedTaskQueue, TaskQueue};
 use crate::common::*;
+use crate::config::{Config, Opt};
 use crate::web::web;
 
+/// Delay before first restart of the Spotify thread after a panic, doubling each consecutive time.
+/// The maximum is kept below the `/healthz` heartbeat timeout, so a restart is not reported as stuck
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// If the Spotify thread ran this long before panicking, the backoff is reset
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(60 * 5);
+
 /// Spotify commander thread
 fn spotify_ctrl(
+    client: &mut Client,
     queue: &LockedTaskQueue,
     global_status: &Arc<RwLock<PlaybackStatus>>,
     global_queue: &Arc<RwLock<TheList>>,
+    global_config: &Arc<RwLock<Config>>,
+    global_heartbeat: &Arc<RwLock<Heartbeat>>,
     running: Arc<AtomicBool>,
 ) -> Result<(), Error> {
-    // Create client wrapper
-    let mut client = Client::new();
-
     let mut innerloop = || -> Result<(), Error> {
         while running.load(Ordering::SeqCst) {
             // Wait for commands from the web-thread
@@ -38,8 +54,13 @@ fn spotify_ctrl(
             if let Some(c) = queue_content {
                 trace!("Got command: {:?}", c);
                 match c {
-                    SpotifyCommand::Request(ri) => client.request(ri.track_id)?,
+                    SpotifyCommand::Request(ri) => {
+                        client.request(&ri, &mut queue.lock().unwrap())?
+                    }
                     SpotifyCommand::Search(sp) => client.search(&sp, &mut queue.lock().unwrap())?,
+                    SpotifyCommand::Browse(bp) => client.browse(&bp, &mut queue.lock().unwrap())?,
+                    SpotifyCommand::Suggest(sp) => client.suggest(&sp, queue)?,
+                    SpotifyCommand::Quota(qp) => client.quota(&qp, &mut queue.lock().unwrap()),
                     SpotifyCommand::SetAuthToken(t) => client.set_auth_token(&t),
                     SpotifyCommand::ClearAuth => client.clear_auth(),
                     SpotifyCommand::ListDevices(lp) => {
@@ -47,6 +68,14 @@ fn spotify_ctrl(
                     }
                     SpotifyCommand::SetActiveDevice(id) => client.set_active_device(id)?,
                     SpotifyCommand::ClearDevice => client.clear_device(),
+                    SpotifyCommand::VoteSkip(c) => client.vote_skip(&c)?,
+                    SpotifyCommand::Pause => client.interrupt()?,
+                    SpotifyCommand::Resume => client.resume_interrupted()?,
+                    SpotifyCommand::SetVolume(v) => client.set_volume(v)?,
+                    SpotifyCommand::Seek(ms) => client.seek(ms)?,
+                    SpotifyCommand::OverrideSchedule(m) => client.override_schedule(m),
+                    SpotifyCommand::SetRadio(r) => client.set_radio(r),
+                    SpotifyCommand::ReloadConfig(c) => client.reload_config(&c),
                 };
             } else {
                 // Wait for new commands
@@ -67,6 +96,19 @@ fn spotify_ctrl(
                 trace!("Updating global queue");
                 let mut q = global_queue.write().unwrap();
                 *q = client.the_list.clone();
+                metrics::LIST_LENGTH.set(q.songs.len() as i64);
+            }
+
+            if *global_config.read().unwrap() != client.cfg {
+                trace!("Updating global config");
+                let mut c = global_config.write().unwrap();
+                *c = client.cfg.clone();
+            }
+
+            {
+                let mut h = global_heartbeat.write().unwrap();
+                h.last_loop = Some(SystemTime::now());
+                h.last_status_update = client.last_status_update;
             }
         }
 
@@ -77,31 +119,150 @@ fn spotify_ctrl(
         let r = innerloop();
         match r {
             Ok(_) => (),
-            Err(e) => warn!("{:?}", e),
+            Err(e) => {
+                metrics::error("spotify_thread");
+                warn!("{:?}", e)
+            }
         }
     }
     Ok(())
 }
 
+/// Get message from `catch_unwind` result
+fn panic_message(e: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(unknown)".into()
+    }
+}
+
+/// Runs `spotify_ctrl`, restarting it with a rebuilt `Client` if it panics
+fn spotify_supervisor(
+    queue: &LockedTaskQueue,
+    global_status: &Arc<RwLock<PlaybackStatus>>,
+    global_queue: &Arc<RwLock<TheList>>,
+    global_config: &Arc<RwLock<Config>>,
+    global_heartbeat: &Arc<RwLock<Heartbeat>>,
+    running: Arc<AtomicBool>,
+) -> Result<(), Error> {
+    // Create client wrapper, picking up any list loaded from the state file
+    let mut client = Client::new(&global_config.read().unwrap());
+    client.the_list = global_queue.read().unwrap().clone();
+
+    let mut backoff = RESTART_BACKOFF_MIN;
+    loop {
+        let started = Instant::now();
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            spotify_ctrl(
+                &mut client,
+                queue,
+                global_status,
+                global_queue,
+                global_config,
+                global_heartbeat,
+                running.clone(),
+            )
+        }));
+        let e = match r {
+            // Only returns when `running` is cleared
+            Ok(r) => return r,
+            Err(e) => e,
+        };
+
+        error!("Spotify thread panicked: {}", panic_message(&e));
+        metrics::SPOTIFY_THREAD_RESTARTS.inc();
+
+        // Panicking whilst holding a lock poisons it, which would make every later use panic too
+        queue.clear_poison();
+        global_status.clear_poison();
+        global_queue.clear_poison();
+        global_config.clear_poison();
+        global_heartbeat.clear_poison();
+        global_heartbeat.write().unwrap().restarts += 1;
+
+        if started.elapsed() > RESTART_BACKOFF_RESET {
+            backoff = RESTART_BACKOFF_MIN;
+        }
+        info!("Restarting Spotify thread in {}s", backoff.as_secs());
+        let wait_until = Instant::now() + backoff;
+        while Instant::now() < wait_until {
+            if !running.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(100));
+        }
+        backoff = std::cmp::min(backoff * 2, RESTART_BACKOFF_MAX);
+
+        // The last list copied to `global_queue` is known to be consistent, unlike the one in `client`
+        let the_list = global_queue.read().unwrap().clone();
+        client = client.rebuild(the_list);
+    }
+}
+
+/// Re-reads the config file whenever `reload` is set (by SIGHUP), passing it to the Spotify thread
+fn config_reloader(
+    opt: &Opt,
+    queue: &LockedTaskQueue,
+    reload: &Arc<AtomicBool>,
+    heartbeat: &Arc<RwLock<Heartbeat>>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::SeqCst) {
+        heartbeat.write().unwrap().last_reload_loop = Some(SystemTime::now());
+        if reload.swap(false, Ordering::SeqCst) {
+            info!("Reloading configuration");
+            match Config::load(opt) {
+                Ok(c) => queue
+                    .lock()
+                    .unwrap()
+                    .queue(SpotifyCommand::ReloadConfig(Box::new(c))),
+                Err(e) => {
+                    metrics::error("config_reload");
+                    error!("Not reloading invalid configuration:\n{}", e)
+                }
+            }
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
+
 /// Start all threads
 fn main() {
-    let cfg = Config {
-        web_host: "0.0.0.0".to_string(),
-        web_port: std::env::var("PORT")
-            .unwrap_or("8081".to_string())
-            .parse::<u32>()
-            .expect("Malformed $PORT value"),
+    let opt = Opt::from_args();
+    let cfg = match Config::load(&opt) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid configuration:\n{}", e);
+            std::process::exit(1);
+        }
     };
-    std::env::set_var("RUST_LOG", "juke=debug");
-    env_logger::init();
+    env_logger::Builder::new().parse_filters(&cfg.log).init();
 
     let running = Arc::new(AtomicBool::new(true));
 
     info!("Setup commencing");
+    metrics::init();
     let status: Arc<RwLock<PlaybackStatus>> = Arc::new(RwLock::new(PlaybackStatus::default()));
 
     let tasks = Arc::new(Mutex::new(TaskQueue::new()));
-    let thelist = Arc::new(RwLock::new(TheList::new()));
+    let initial_list = match &cfg.state_file {
+        Some(path) if path.exists() => match TheList::load(path) {
+            Ok(l) => {
+                info!("Loaded {} songs from {}", l.songs.len(), path.display());
+                l
+            }
+            Err(e) => {
+                warn!("Could not load state from {}: {}", path.display(), e);
+                TheList::new()
+            }
+        },
+        _ => TheList::new(),
+    };
+    metrics::LIST_LENGTH.set(initial_list.songs.len() as i64);
+    let thelist = Arc::new(RwLock::new(initial_list));
 
     let r = running.clone();
     ctrlc::set_handler(move || {
@@ -117,13 +278,21 @@ fn main() {
     })
     .expect("Error setting Ctrl-C handler");
 
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload.clone())
+        .expect("Error setting SIGHUP handler");
+    let config = Arc::new(RwLock::new(cfg.clone()));
+    let heartbeat = Arc::new(RwLock::new(Heartbeat::default()));
+
     info!("Starting web thread");
     let thread_web = {
         let q1 = tasks.clone();
         let s1 = status.clone();
         let l1 = thelist.clone();
+        let c1 = config.clone();
+        let h1 = heartbeat.clone();
         let r1 = running.clone();
-        thread::spawn(move || web(q1, s1, l1, r1, &cfg))
+        thread::spawn(move || web(q1, s1, l1, c1, h1, r1))
     };
 
     info!("Starting Spotify thread");
@@ -131,12 +300,29 @@ fn main() {
         let q2 = tasks.clone();
         let s2 = status.clone();
         let l2 = thelist.clone();
+        let c2 = config.clone();
+        let h2 = heartbeat.clone();
         let r2 = running.clone();
-        thread::spawn(move || spotify_ctrl(&q2, &s2, &l2, r2))
+        thread::spawn(move || spotify_supervisor(&q2, &s2, &l2, &c2, &h2, r2))
+    };
+
+    info!("Starting config reload thread");
+    let thread_reload = {
+        let q3 = tasks.clone();
+        let h3 = heartbeat.clone();
+        let r3 = running.clone();
+        thread::spawn(move || config_reloader(&opt, &q3, &reload, &h3, r3))
     };
 
     thread_spotify.join().unwrap().unwrap();
     thread_web.join().unwrap();
+    thread_reload.join().unwrap();
 
-    // TODO: Save TheList etc?
+    if let Some(path) = &cfg.state_file {
+        let l = thelist.read().unwrap();
+        match l.save(path) {
+            Ok(_) => info!("Saved {} songs to {}", l.songs.len(), path.display()),
+            Err(e) => error!("Could not save state to {}: {}", path.display(), e),
+        }
+    }
 }
@@ -0,0 +1,156 @@
+use std::time::Instant;
+
+use failure::Error;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
+use rspotify::spotify::client::ApiError;
+
+use crate::common::ClientResult;
+
+lazy_static! {
+    /// Time taken by each Spotify web API call
+    pub static ref SPOTIFY_API_DURATION: HistogramVec = register_histogram_vec!(
+        "juke_spotify_api_duration_seconds",
+        "Spotify web API call latency",
+        &["endpoint"]
+    )
+    .unwrap();
+
+    /// Errors, by kind
+    pub static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "juke_errors_total",
+        "Errors encountered, by kind",
+        &["kind"]
+    )
+    .unwrap();
+
+    /// Number of songs in `TheList`
+    pub static ref LIST_LENGTH: IntGauge =
+        register_int_gauge!("juke_list_length", "Songs waiting in the list").unwrap();
+
+    /// Songs sent to Spotify for playback
+    pub static ref SONGS_PLAYED: IntCounter =
+        register_int_counter!("juke_songs_played_total", "Songs started from the list").unwrap();
+
+    /// Open web socket connections
+    pub static ref WEBSOCKET_CLIENTS: IntGauge = register_int_gauge!(
+        "juke_websocket_clients",
+        "Connected web socket clients"
+    )
+    .unwrap();
+
+    /// Number of times the Spotify thread panicked and was restarted
+    pub static ref SPOTIFY_THREAD_RESTARTS: IntCounter = register_int_counter!(
+        "juke_spotify_thread_restarts_total",
+        "Spotify thread restarts after a panic"
+    )
+    .unwrap();
+
+    /// Commands waiting in `TaskQueue`
+    pub static ref TASK_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "juke_task_queue_depth",
+        "Commands waiting for the Spotify thread"
+    )
+    .unwrap();
+
+    /// Cache lookups, by cache and whether the entry was found
+    pub static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "juke_cache_lookups_total",
+        "Cache lookups, by cache and result (hit or miss)",
+        &["cache", "result"]
+    )
+    .unwrap();
+
+    /// How long commands sat in `TaskQueue` before being handled
+    pub static ref TASK_QUEUE_WAIT: Histogram = register_histogram!(
+        "juke_task_queue_wait_seconds",
+        "Time commands wait before the Spotify thread picks them up"
+    )
+    .unwrap();
+}
+
+/// Register all metrics, so they are reported before first being used
+pub fn init() {
+    lazy_static::initialize(&SPOTIFY_API_DURATION);
+    lazy_static::initialize(&ERRORS);
+    lazy_static::initialize(&LIST_LENGTH);
+    lazy_static::initialize(&SONGS_PLAYED);
+    lazy_static::initialize(&WEBSOCKET_CLIENTS);
+    lazy_static::initialize(&SPOTIFY_THREAD_RESTARTS);
+    lazy_static::initialize(&TASK_QUEUE_DEPTH);
+    lazy_static::initialize(&TASK_QUEUE_WAIT);
+    lazy_static::initialize(&CACHE_LOOKUPS);
+}
+
+/// Increment error counter for given kind
+pub fn error(kind: &str) {
+    ERRORS.with_label_values(&[kind]).inc();
+}
+
+/// Run a Spotify API call, recording how long it took and whether it failed
+pub fn time_api<T, F>(endpoint: &str, f: F) -> ClientResult<T>
+where
+    F: FnOnce() -> ClientResult<T>,
+{
+    let start = Instant::now();
+    let r = f();
+    SPOTIFY_API_DURATION
+        .with_label_values(&[endpoint])
+        .observe(start.elapsed().as_secs_f64());
+    if let Err(e) = &r {
+        error(api_error_kind(e));
+    }
+    r
+}
+
+/// Error kind for a failed Spotify API call, from the HTTP status where there is one
+fn api_error_kind(e: &Error) -> &'static str {
+    match e.downcast_ref::<ApiError>() {
+        Some(ApiError::Unauthorized) => "spotify_unauthorized",
+        Some(ApiError::RateLimited(_)) => "spotify_rate_limited",
+        Some(ApiError::Other(status)) if *status >= 500 => "spotify_server_error",
+        Some(ApiError::Other(_)) => "spotify_client_error",
+        // Network failures and responses that could not be parsed
+        None => "spotify_api",
+    }
+}
+
+/// All metrics in Prometheus text format
+pub fn gather() -> Vec<u8> {
+    let mut buf = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .unwrap();
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::format_err;
+
+    #[test]
+    fn api_errors_by_status() {
+        assert_eq!(
+            api_error_kind(&ApiError::Unauthorized.into()),
+            "spotify_unauthorized"
+        );
+        assert_eq!(
+            api_error_kind(&ApiError::RateLimited(Some(3)).into()),
+            "spotify_rate_limited"
+        );
+        assert_eq!(
+            api_error_kind(&ApiError::Other(502).into()),
+            "spotify_server_error"
+        );
+        assert_eq!(
+            api_error_kind(&ApiError::Other(404).into()),
+            "spotify_client_error"
+        );
+        assert_eq!(api_error_kind(&format_err!("timed out")), "spotify_api");
+    }
+}
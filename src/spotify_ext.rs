@@ -0,0 +1,89 @@
+use failure::format_err;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH};
+use serde_json::json;
+
+use rspotify::spotify::client::{ApiError, Spotify};
+
+use crate::common::ClientResult;
+
+/// Bearer token header value, in the same way `rspotify` does it
+fn auth_header(spotify: &Spotify) -> ClientResult<String> {
+    let token = match (&spotify.access_token, &spotify.client_credentials_manager) {
+        (Some(t), _) => t.clone(),
+        (None, Some(ccm)) => ccm.get_access_token(),
+        (None, None) => return Err(format_err!("Client not authenticated")),
+    };
+    Ok(format!("Bearer {}", token))
+}
+
+/// Send request, turning non-success statuses into `ApiError`
+fn send(spotify: &Spotify, builder: reqwest::RequestBuilder) -> ClientResult<()> {
+    let response = builder
+        .header(AUTHORIZATION, auth_header(spotify)?)
+        .send()?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(ApiError::from(&response).into())
+    }
+}
+
+/// Add a track to the end of the device's play queue, to be played after the current one
+pub fn add_to_queue(spotify: &Spotify, uri: &str, device_id: Option<String>) -> ClientResult<()> {
+    let mut params = vec![("uri", uri.to_string())];
+    if let Some(d) = device_id {
+        params.push(("device_id", d));
+    }
+    let builder = reqwest::Client::new()
+        .post("https://api.spotify.com/v1/me/player/queue")
+        .query(&params)
+        .header(CONTENT_LENGTH, 0);
+    send(spotify, builder)
+}
+
+/// Play a track starting part way through, which `Spotify::start_playback` has no option for
+pub fn start_playback_at(
+    spotify: &Spotify,
+    uri: &str,
+    position_ms: u32,
+    device_id: Option<String>,
+) -> ClientResult<()> {
+    let params: Vec<(&str, String)> = device_id.map(|d| ("device_id", d)).into_iter().collect();
+    let body = json!({
+        "uris": [uri],
+        "position_ms": position_ms,
+    });
+    let builder = reqwest::Client::new()
+        .put("https://api.spotify.com/v1/me/player/play")
+        .query(&params)
+        .json(&body);
+    send(spotify, builder)
+}
+
+/// Move playback to another device. `Spotify::transfer_playback` misspells `device_ids`, so
+/// Spotify rejects it
+pub fn transfer_playback(spotify: &Spotify, device_id: &str, play: bool) -> ClientResult<()> {
+    let body = json!({
+        "device_ids": [device_id],
+        "play": play,
+    });
+    let builder = reqwest::Client::new()
+        .put("https://api.spotify.com/v1/me/player")
+        .json(&body);
+    send(spotify, builder)
+}
+
+/// Country of the user's account, used as the market for searches. `Spotify::me` cannot be used
+/// as it fails unless the birthdate and email scopes were granted, and the country itself is only
+/// included with the `user-read-private` scope
+pub fn user_country(spotify: &Spotify) -> ClientResult<Option<String>> {
+    let builder = reqwest::Client::new().get("https://api.spotify.com/v1/me");
+    let mut response = builder
+        .header(AUTHORIZATION, auth_header(spotify)?)
+        .send()?;
+    if !response.status().is_success() {
+        return Err(ApiError::from(&response).into());
+    }
+    let user: serde_json::Value = response.json()?;
+    Ok(user["country"].as_str().map(|c| c.to_string()))
+}
@@ -1,63 +1,318 @@
+use lazy_static::lazy_static;
 use log::{info, trace};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use serde_derive::Serialize;
-use serde_json;
 
 use rouille::{router, try_or_400, websocket, Request, Response};
 
 use crate::client::TheList;
 use crate::commands::LockedTaskQueue;
 use crate::common::{
-    CommandResponse, CommandResponseDataType, Config, DeviceListParams, DeviceListResult,
-    PlaybackStatus, PlaylistInfo, SearchParams, SearchResult, SongRequestInfo, SpotifyCommand,
-    TaskID,
+    duration_as_millis, BrowseKind, BrowseParams, CommandResponse, CommandResponseDataType,
+    DeviceListParams, DeviceListResult, Heartbeat, PlaybackState, PlaybackStatus, PlaylistInfo,
+    QuotaInfo, QuotaParams, RequestResult, SearchKind, SearchParams, SearchResult, ServerTime,
+    SongRequestInfo, SpotifyCommand, SuggestParams, SuggestResult, TaskID,
 };
+use crate::config::Config;
+use crate::metrics;
 
 #[derive(Debug, Serialize)]
 pub enum WebResponse<'a> {
     Success,
-    Status(PlaybackStatus, PlaylistInfo),
+    Status(Box<PlaybackStatus>, PlaylistInfo, ServerTime),
     Search(SearchResult),
+    Suggest(SuggestResult),
+    Quota(QuotaInfo),
+    Request(RequestResult),
     Queue(&'a TheList),
     DeviceList(DeviceListResult),
     Error(String),
 }
 
-/// Wait for given task ID
-fn wait_for_task(queue: &LockedTaskQueue, tid: TaskID) -> CommandResponse {
-    let duration_ms = 15_000;
+lazy_static! {
+    /// Random key for `client_id`, so an ID can't be traced back by hashing likely addresses
+    static ref CLIENT_ID_KEY: RandomState = RandomState::new();
+}
+
+/// Address of the client. With `trust_forwarded_for`, the last address in `X-Forwarded-For`,
+/// which is the one the reverse proxy saw; earlier ones could have been made up by the client
+fn client_addr(request: &Request, trust_forwarded_for: bool) -> IpAddr {
+    request
+        .header("X-Forwarded-For")
+        .filter(|_| trust_forwarded_for)
+        .and_then(|h| h.rsplit(',').next())
+        .and_then(|a| a.trim().parse().ok())
+        .unwrap_or_else(|| request.remote_addr().ip())
+}
+
+/// Identifies the client by its address, without revealing it. Set by the server, so used for
+/// anything clients should not be able to get around, such as skip votes and quotas. IDs change
+/// when Jukeula restarts
+fn client_id(request: &Request, global_config: &Arc<RwLock<Config>>) -> String {
+    let trust_forwarded_for = global_config.read().unwrap().web.trust_forwarded_for;
+    let hash = CLIENT_ID_KEY.hash_one(client_addr(request, trust_forwarded_for));
+    format!("guest-{:06x}", hash & 0xff_ffff)
+}
+
+/// A thread is considered stuck if it has not gone around its loop for this long. Longer than any
+/// wait in the loop, so a thread that is merely waiting to retry is not reported as stuck
+const HEARTBEAT_TIMEOUT_MS: u64 = 120_000;
+
+/// Playback status is considered stale if not updated for this long (or twice the longest polling interval, if longer)
+const STATUS_STALE_MS: u64 = 10_000;
+
+/// Longer requester names given to /api/request are truncated
+const MAX_REQUESTER_LEN: usize = 40;
+
+/// Requester named by the `requester` parameter, or else the client ID. Only for display, as
+/// clients can give any name
+fn requester(request: &Request, global_config: &Arc<RwLock<Config>>) -> String {
+    request
+        .get_param("requester")
+        .map(|r| r.trim().chars().take(MAX_REQUESTER_LEN).collect::<String>())
+        .filter(|r| !r.is_empty())
+        .unwrap_or_else(|| client_id(request, global_config))
+}
+
+/// Response for `/healthz`
+#[derive(Debug, Serialize)]
+struct Health {
+    healthy: bool,
+    spotify_thread_last_seen_ms: Option<u64>,
+    reload_thread_last_seen_ms: Option<u64>,
+    spotify_thread_restarts: u32,
+}
+
+/// Whether a thread last seen `last_seen` ms ago is still running
+fn thread_alive(last_seen: Option<u64>) -> bool {
+    last_seen
+        .map(|ms| ms < HEARTBEAT_TIMEOUT_MS)
+        .unwrap_or(false)
+}
+
+/// Response for `/readyz`
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    state: PlaybackState,
+    authenticated: bool,
+    device_selected: bool,
+    last_status_update_ms: Option<u64>,
+}
+
+/// Milliseconds elapsed since given time
+fn ms_since(t: Option<SystemTime>) -> Option<u64> {
+    t.and_then(|t| t.elapsed().ok()).map(duration_as_millis)
+}
+
+/// How long `/api/request` waits to report whether the song was added. The request waits behind
+/// any Spotify calls in progress, which can be slow, so this may not be long enough; the song is
+/// still added afterwards and shows up in the list
+const REQUEST_WAIT_MS: u64 = 2_000;
+
+/// Wait up to `duration_ms` for given task ID. If it takes longer the response is dropped when
+/// it comes
+fn try_wait_for_task(
+    queue: &LockedTaskQueue,
+    tid: TaskID,
+    duration_ms: u64,
+) -> Option<CommandResponse> {
     let step = 100;
     for _ in 0..(duration_ms / step) {
         {
             let response = queue.lock().unwrap().wait(tid);
             if let Some(r) = response {
-                return r;
+                return Some(r);
             }
             // Drop lock
         }
 
         sleep(Duration::from_millis(step));
     }
+    queue.lock().unwrap().abandon(tid);
+    None
+}
+
+/// Wait for given task ID
+fn wait_for_task(queue: &LockedTaskQueue, tid: TaskID) -> CommandResponse {
+    try_wait_for_task(queue, tid, 15_000).unwrap_or_else(|| {
+        // Waited too long
+        metrics::error("task_timeout");
+        CommandResponse {
+            tid,
+            value: CommandResponseDataType::Error("Timed out".into()),
+        }
+    })
+}
+
+/// Counts a connected websocket client in `WEBSOCKET_CLIENTS` for as long as it exists, so the
+/// count goes down however the connection ends
+struct WebsocketClient;
+
+impl WebsocketClient {
+    fn new() -> WebsocketClient {
+        metrics::WEBSOCKET_CLIENTS.inc();
+        WebsocketClient
+    }
+}
+
+impl Drop for WebsocketClient {
+    fn drop(&mut self) {
+        metrics::WEBSOCKET_CLIENTS.dec();
+    }
+}
+
+/// Boolean query parameter, e.g `?hide_explicit=1`
+fn flag_param(request: &Request, name: &str) -> bool {
+    match request.get_param(name) {
+        Some(v) => v == "1" || v == "true",
+        None => false,
+    }
+}
+
+/// Paging parameters from query string: `offset` and `limit`
+fn page_params(request: &Request) -> Result<(u32, Option<u32>), String> {
+    let offset = match request.get_param("offset") {
+        Some(o) => o.parse().map_err(|_| format!("Malformed offset {:?}", o))?,
+        None => 0,
+    };
+    let limit = match request.get_param("limit") {
+        Some(l) => match l.parse() {
+            Ok(n) if (1..=50).contains(&n) => Some(n),
+            _ => return Err(format!("limit must be between 1 and 50 (got {:?})", l)),
+        },
+        None => None,
+    };
+    Ok((offset, limit))
+}
+
+/// Search parameters from query string: paging, `artist`, `album`, `playable_only` and
+/// `hide_explicit`
+fn search_params(
+    request: &Request,
+    query: String,
+    kind: SearchKind,
+    tid: TaskID,
+) -> Result<SpotifyCommand, String> {
+    let (offset, limit) = page_params(request)?;
+    Ok(SpotifyCommand::Search(SearchParams {
+        query,
+        kind,
+        tid,
+        offset,
+        limit,
+        artist: request.get_param("artist"),
+        album: request.get_param("album"),
+        playable_only: flag_param(request, "playable_only"),
+        hide_explicit: flag_param(request, "hide_explicit"),
+    }))
+}
+
+/// Browse parameters from query string: paging, `playable_only` and `hide_explicit`
+fn browse_params(
+    request: &Request,
+    kind: BrowseKind,
+    tid: TaskID,
+) -> Result<SpotifyCommand, String> {
+    let (offset, limit) = page_params(request)?;
+    Ok(SpotifyCommand::Browse(BrowseParams {
+        kind,
+        tid,
+        offset,
+        limit,
+        playable_only: flag_param(request, "playable_only"),
+        hide_explicit: flag_param(request, "hide_explicit"),
+    }))
+}
+
+/// Queue search or browse command and wait for the results
+fn search<F>(queue: &LockedTaskQueue, command: F) -> Response
+where
+    F: FnOnce(TaskID) -> Result<SpotifyCommand, String>,
+{
+    // Queue search task and drop lock
+    let tid: TaskID = {
+        let mut q = queue.lock().unwrap();
+        let tid = q.get_task_id();
+        match command(tid) {
+            Ok(c) => q.queue(c),
+            Err(e) => return Response::json(&WebResponse::Error(e)).with_status_code(400),
+        }
+        tid
+    };
+
+    let r = wait_for_task(queue, tid);
+    let inner = match r.value {
+        CommandResponseDataType::Search(d) => WebResponse::Search(d),
+        CommandResponseDataType::Error(e) => WebResponse::Error(e),
+        _ => WebResponse::Error("Unexpected response from search command".into()),
+    };
+    Response::json(&inner)
+}
+
+/// Suggestions for `/api/suggest`. Waits briefly for further keystrokes first, so only the
+/// latest query from each client is looked up
+fn suggest(
+    request: &Request,
+    queue: &LockedTaskQueue,
+    global_config: &Arc<RwLock<Config>>,
+) -> Response {
+    let prefix = request.get_param("q").unwrap_or_default();
+    let client = request
+        .get_param("client")
+        .unwrap_or_else(|| client_id(request, global_config));
+    let cfg = global_config.read().unwrap().suggest.clone();
+    if prefix.trim().chars().count() < cfg.min_length {
+        return Response::json(&WebResponse::Suggest(SuggestResult {
+            prefix,
+            ..SuggestResult::default()
+        }));
+    }
 
-    // Waited too long
-    CommandResponse {
-        tid: tid,
-        value: CommandResponseDataType::Error("Timed out".into()),
+    let tid = queue.lock().unwrap().start_suggestion(&client);
+    sleep(Duration::from_millis(cfg.debounce_ms.into()));
+    {
+        let mut q = queue.lock().unwrap();
+        if !q.is_latest_suggestion(&client, tid) {
+            return Response::json(&WebResponse::Suggest(SuggestResult::superseded(&prefix)));
+        }
+        q.queue_suggestion(SuggestParams {
+            prefix,
+            client: client.clone(),
+            tid,
+        });
     }
+
+    let r = wait_for_task(queue, tid);
+    queue.lock().unwrap().finish_suggestion(&client, tid);
+    let inner = match r.value {
+        CommandResponseDataType::Suggest(d) => WebResponse::Suggest(d),
+        CommandResponseDataType::Error(e) => WebResponse::Error(e),
+        _ => WebResponse::Error("Unexpected response from suggest command".into()),
+    };
+    Response::json(&inner)
 }
 
+/// Handle messages from a web socket client. Admin commands are only accepted if the connection
+/// was opened with the admin token
 fn websocket_handling_thread(
     mut websocket: websocket::Websocket,
+    queue: &LockedTaskQueue,
     global_status: &Arc<RwLock<PlaybackStatus>>,
     global_queue: &Arc<RwLock<TheList>>,
+    is_admin: bool,
 ) {
+    let _client = WebsocketClient::new();
     // We wait for a new message to come from the websocket.
     while let Some(message) = websocket.next() {
-        match message {
+        let reply = match message {
             websocket::Message::Text(txt) => {
                 if txt == "status" {
                     let s = global_status.read().unwrap().clone();
@@ -65,22 +320,32 @@ fn websocket_handling_thread(
                     let qi = crate::common::PlaylistInfo {
                         playlist_version: q.version,
                     };
-                    let info = WebResponse::Status(s, qi);
-
-                    let t = serde_json::to_string(&info).unwrap();
-                    websocket.send_text(&t).unwrap();
+                    let info = WebResponse::Status(Box::new(s), qi, ServerTime::now());
+                    serde_json::to_string(&info).unwrap()
                 } else if txt == "queue" {
                     let q = global_queue.read().unwrap();
                     let info = WebResponse::Queue(&q);
-                    let t = serde_json::to_string(&info).unwrap();
-                    websocket.send_text(&t).unwrap();
+                    serde_json::to_string(&info).unwrap()
+                } else if let Some(position) = txt.strip_prefix("seek ") {
+                    let info = match (is_admin, position.trim().parse::<u32>()) {
+                        (false, _) => WebResponse::Error("Not authorised".into()),
+                        (true, Err(_)) => WebResponse::Error("Invalid seek position".into()),
+                        (true, Ok(ms)) => {
+                            queue.lock().unwrap().queue(SpotifyCommand::Seek(ms));
+                            WebResponse::Success
+                        }
+                    };
+                    serde_json::to_string(&info).unwrap()
                 } else {
-                    websocket
-                        .send_text("{\"error\": \"Unknown command\"}")
-                        .unwrap();
+                    "{\"error\": \"Unknown command\"}".to_string()
                 }
             }
-            websocket::Message::Binary(_) => (),
+            websocket::Message::Binary(_) => continue,
+        };
+        if let Err(e) = websocket.send_text(&reply) {
+            // Client went away
+            trace!("Could not send to web socket: {:?}", e);
+            break;
         }
         sleep(Duration::from_millis(10))
     }
@@ -97,13 +362,48 @@ fn generate_random_string(length: usize) -> String {
         .collect()
 }
 
-static CONTENT_INDEX: &'static str = include_str!("../static/index.html");
+/// Minimal escaping for inserting text into HTML
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Admin token from `X-Admin-Token` header or `admin_token` parameter
+fn admin_token(request: &Request) -> Option<String> {
+    request
+        .header("X-Admin-Token")
+        .map(|t| t.to_string())
+        .or_else(|| request.get_param("admin_token"))
+}
+
+/// Error response if request is not from an admin
+fn require_admin(request: &Request, global_config: &Arc<RwLock<Config>>) -> Option<Response> {
+    let admin = &global_config.read().unwrap().admin;
+    if admin.token.is_none() {
+        Some(
+            Response::json(&WebResponse::Error(
+                "Admin actions are disabled, set admin.token to enable".into(),
+            ))
+            .with_status_code(403),
+        )
+    } else if !admin.is_admin(admin_token(request).as_deref()) {
+        Some(Response::json(&WebResponse::Error("Not authorised".into())).with_status_code(403))
+    } else {
+        None
+    }
+}
+
+static CONTENT_INDEX: &str = include_str!("../static/index.html");
 
 fn handle_response(
     request: &Request,
     queue: &LockedTaskQueue,
     global_status: &Arc<RwLock<PlaybackStatus>>,
     global_queue: &Arc<RwLock<TheList>>,
+    global_config: &Arc<RwLock<Config>>,
+    global_heartbeat: &Arc<RwLock<Heartbeat>>,
 ) -> Response {
     if let Some(request) = request.remove_prefix("/static") {
         if !cfg!(debug_assertions) {
@@ -148,47 +448,194 @@ fn handle_response(
         (GET) (/) => {
             // Index
             // FIXME: Serve status stuff
-            Response::html(CONTENT_INDEX)
+            let branding = global_config.read().unwrap().branding.clone();
+            Response::html(CONTENT_INDEX
+                .replace("{{title}}", &escape_html(&branding.title))
+                .replace("{{name}}", &escape_html(&branding.name)))
         },
         (GET) (/ws) => {
-            let (response, websocket) = try_or_400!(websocket::start(&request, Some("juke")));
+            let is_admin = require_admin(request, global_config).is_none();
+            let (response, websocket) = try_or_400!(websocket::start(request, Some("juke")));
+            let q = queue.clone();
             let gs = global_status.clone();
             let gq = global_queue.clone();
             std::thread::spawn(move || {
                 let ws = websocket.recv().unwrap();
-                websocket_handling_thread(ws, &gs, &gq);
+                websocket_handling_thread(ws, &q, &gs, &gq, is_admin);
             });
             response
         },
 
         (GET) (/api/request/{track_id:String}) => {
             // Add song to the list
-            queue.lock().unwrap().queue(SpotifyCommand::Request(SongRequestInfo{track_id: track_id}));
+            let requester = requester(request, global_config);
+            let client = client_id(request, global_config);
+            let tid: TaskID = {
+                let mut q = queue.lock().unwrap();
+                let tid = q.get_task_id();
+                q.queue(SpotifyCommand::Request(SongRequestInfo {
+                    track_id,
+                    tid,
+                    requester,
+                    client,
+                }));
+                tid
+            };
+
+            // Don't tie up a web worker while Spotify is slow, just say it was accepted
+            let r = match try_wait_for_task(queue, tid, REQUEST_WAIT_MS) {
+                Some(r) => r,
+                None => return Response::json(&WebResponse::Success).with_status_code(202),
+            };
+            let inner = match r.value {
+                CommandResponseDataType::Request(d) => WebResponse::Request(d),
+                CommandResponseDataType::Error(e) => WebResponse::Error(e),
+                _ => WebResponse::Error("Unexpected response from command in /api/request/...".into()),
+            };
+            Response::json(&inner)
+        },
+        (GET) (/api/quota) => {
+            // Remaining quota of the client making this request
+            let client = client_id(request, global_config);
+            let tid: TaskID = {
+                let mut q = queue.lock().unwrap();
+                let tid = q.get_task_id();
+                q.queue(SpotifyCommand::Quota(QuotaParams { client, tid }));
+                tid
+            };
+
+            let r = wait_for_task(queue, tid);
+            let inner = match r.value {
+                CommandResponseDataType::Quota(d) => WebResponse::Quota(d),
+                CommandResponseDataType::Error(e) => WebResponse::Error(e),
+                _ => WebResponse::Error("Unexpected response from command in /api/quota".into()),
+            };
+            Response::json(&inner)
+        },
+        (GET) (/api/skip) => {
+            let client = client_id(request, global_config);
+            queue.lock().unwrap().queue(SpotifyCommand::VoteSkip(client));
+            Response::json(&WebResponse::Success)
+        },
+
+        (GET) (/api/admin/pause) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::Pause);
+            Response::json(&WebResponse::Success)
+        },
+        (GET) (/api/admin/resume) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::Resume);
+            Response::json(&WebResponse::Success)
+        },
+
+        (GET) (/api/admin/seek/{position_ms:u32}) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::Seek(position_ms));
+            Response::json(&WebResponse::Success)
+        },
+        (GET) (/api/admin/volume/{percent:u8}) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            if percent > 100 {
+                return Response::json(&WebResponse::Error("Volume must be 0 to 100".into())).with_status_code(400);
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::SetVolume(percent));
+            Response::json(&WebResponse::Success)
+        },
+
+        (GET) (/api/admin/radio/on) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::SetRadio(true));
+            Response::json(&WebResponse::Success)
+        },
+
+        (GET) (/api/admin/radio/off) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::SetRadio(false));
+            Response::json(&WebResponse::Success)
+        },
+
+        (GET) (/api/admin/schedule/override/{minutes:u32}) => {
+            if let Some(r) = require_admin(request, global_config) {
+                return r;
+            }
+            queue.lock().unwrap().queue(SpotifyCommand::OverrideSchedule(minutes));
             Response::json(&WebResponse::Success)
         },
 
+        (GET) (/healthz) => {
+            // Web thread is evidently alive, so check the others
+            let heartbeat = global_heartbeat.read().unwrap().clone();
+            let spotify = ms_since(heartbeat.last_loop);
+            let reload = ms_since(heartbeat.last_reload_loop);
+            let healthy = thread_alive(spotify) && thread_alive(reload);
+            let info = Health{
+                healthy,
+                spotify_thread_last_seen_ms: spotify,
+                reload_thread_last_seen_ms: reload,
+                spotify_thread_restarts: heartbeat.restarts,
+            };
+            Response::json(&info).with_status_code(if healthy { 200 } else { 503 })
+        },
+        (GET) (/readyz) => {
+            let state = global_status.read().unwrap().state;
+            let last_update = ms_since(global_heartbeat.read().unwrap().last_status_update);
+            let stale_ms = std::cmp::max(
+                STATUS_STALE_MS,
+                2 * u64::from(global_config.read().unwrap().polling.longest_ms()),
+            );
+
+            let authenticated = last_update.is_some() && state != PlaybackState::NoAuth;
+            let device_selected = authenticated && state != PlaybackState::NoDevice;
+            let recent = last_update.map(|ms| ms < stale_ms).unwrap_or(false);
+            let ready = authenticated && device_selected && recent;
+
+            let info = Readiness{
+                ready,
+                state,
+                authenticated,
+                device_selected,
+                last_status_update_ms: last_update,
+            };
+            Response::json(&info).with_status_code(if ready { 200 } else { 503 })
+        },
+        (GET) (/metrics) => {
+            Response::from_data("text/plain; version=0.0.4", metrics::gather())
+        },
         (GET) (/api/status) => {
             let s = global_status.read().unwrap().clone();
             let q = global_queue.read().unwrap();
             let qi = crate::common::PlaylistInfo {
                 playlist_version: q.version,
             };
-            Response::json(&WebResponse::Status(s, qi))
+            Response::json(&WebResponse::Status(Box::new(s), qi, ServerTime::now()))
         },
         (GET) (/api/device/list) => {
             trace!("Request for device list");
             let tid: TaskID = {
                 let mut q = queue.lock().unwrap();
                 let tid = q.get_task_id();
-                q.queue(SpotifyCommand::ListDevices(DeviceListParams{tid: tid}));
+                q.queue(SpotifyCommand::ListDevices(DeviceListParams{tid}));
                 tid
             };
             trace!("Awaiting task");
-            let r = wait_for_task(&queue, tid);
+            let r = wait_for_task(queue, tid);
             trace!("Got task list");
             let inner = match r.value {
                 CommandResponseDataType::DeviceList(d) => WebResponse::DeviceList(d),
-                _ => WebResponse::Error(format!("Unexpected response from command in /api/device/list")),
+                _ => WebResponse::Error("Unexpected response from command in /api/device/list".into()),
             };
             trace!("Responding with task list to web client");
             Response::json(&inner)
@@ -202,25 +649,30 @@ fn handle_response(
             Response::text("{\"result\":\"ok\"}")
         },
         (GET) (/search/track/{term:String}) => {
-            // Queue search task and drop lock
-            let tid: TaskID = {
-                let mut q = queue.lock().unwrap();
-                let tid = q.get_task_id();
-                q.queue(SpotifyCommand::Search(SearchParams{tid: tid, title: term}));
-                tid
-            };
-
-            let r = wait_for_task(&queue, tid);
-            let inner = match r.value {
-                CommandResponseDataType::Search(d) => WebResponse::Search(d),
-                _ => WebResponse::Error(format!("Unexpected response from command in /search/track/...")),
-            };
-            Response::json(&inner)
+            search(queue, |tid| search_params(request, term, SearchKind::Track, tid))
+        },
+        (GET) (/search/artist/{term:String}) => {
+            search(queue, |tid| search_params(request, term, SearchKind::Artist, tid))
+        },
+        (GET) (/search/album/{term:String}) => {
+            search(queue, |tid| search_params(request, term, SearchKind::Album, tid))
+        },
+        (GET) (/api/suggest) => {
+            suggest(request, queue, global_config)
+        },
+        (GET) (/browse/artist/{id:String}/top-tracks) => {
+            search(queue, |tid| browse_params(request, BrowseKind::ArtistTopTracks(id), tid))
+        },
+        (GET) (/browse/artist/{id:String}/albums) => {
+            search(queue, |tid| browse_params(request, BrowseKind::ArtistAlbums(id), tid))
+        },
+        (GET) (/browse/album/{id:String}/tracks) => {
+            search(queue, |tid| browse_params(request, BrowseKind::AlbumTracks(id), tid))
         },
         (GET) (/auth) => {
             // FIXME: Move elsewhere
             let oauth = rspotify::spotify::oauth2::SpotifyOAuth::default()
-                .scope("user-read-playback-state user-modify-playback-state")
+                .scope(&global_config.read().unwrap().spotify.scope_string())
                 .build();
 
             let state = generate_random_string(16);
@@ -259,13 +711,24 @@ pub fn web(
     queue: LockedTaskQueue,
     global_status: Arc<RwLock<PlaybackStatus>>,
     global_queue: Arc<RwLock<TheList>>,
+    global_config: Arc<RwLock<Config>>,
+    global_heartbeat: Arc<RwLock<Heartbeat>>,
     running: Arc<AtomicBool>,
-    cfg: &Config,
 ) {
-    let addr = format!("{}:{}", cfg.web_host, cfg.web_port);
+    let addr = {
+        let cfg = global_config.read().unwrap();
+        format!("{}:{}", cfg.web.host, cfg.web.port)
+    };
     info!("Listening on http://{}", &addr);
     let srv = rouille::Server::new(&addr, move |request| {
-        handle_response(request, &queue.clone(), &global_status, &global_queue)
+        handle_response(
+            request,
+            &queue.clone(),
+            &global_status,
+            &global_queue,
+            &global_config,
+            &global_heartbeat,
+        )
     })
     .unwrap();
 
@@ -274,3 +737,41 @@ pub fn web(
         sleep(Duration::from_millis(10)); // FIXME: https://github.com/tomaka/rouille/issues/200
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(forwarded_for: &str) -> Request {
+        Request::fake_http_from(
+            "10.0.0.1:1234".parse().unwrap(),
+            "GET",
+            "/api/quota",
+            vec![("X-Forwarded-For".into(), forwarded_for.into())],
+            vec![],
+        )
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_only_when_trusted() {
+        let r = request("203.0.113.9, 192.0.2.7");
+        assert_eq!(client_addr(&r, false), ip("10.0.0.1"));
+        assert_eq!(client_addr(&r, true), ip("192.0.2.7"));
+        assert_eq!(client_addr(&request("unknown"), true), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn client_id_follows_address() {
+        let mut cfg = Config::default();
+        cfg.web.trust_forwarded_for = true;
+        let cfg = Arc::new(RwLock::new(cfg));
+        let id = client_id(&request("192.0.2.7"), &cfg);
+        assert!(id.starts_with("guest-"), "{}", id);
+        assert_eq!(client_id(&request("203.0.113.9, 192.0.2.7"), &cfg), id);
+        assert_ne!(client_id(&request("192.0.2.8"), &cfg), id);
+    }
+}
@@ -53,6 +53,24 @@ function formatDuration(time) {
     return ret;
 }
 
+function artistNames(song) {
+    return song.artists.map((a) => a.name).join(", ");
+}
+
+function albumDescription(song) {
+    return song.album.name + (song.album.release_year ? " (" + song.album.release_year + ")" : "");
+}
+
+function albumImage(song, width) {
+    // Smallest image at least as wide as requested, images are ordered largest first
+    let images = song.album.images;
+    if (images.length == 0) {
+        return "/static/thejuke.png";
+    }
+    let fits = images.filter((i) => i.width === null || i.width >= width);
+    return (fits.length > 0 ? fits[fits.length - 1] : images[0]).url;
+}
+
 class ButtonDebounce extends React.Component {
     constructor(props) {
         super(props);
@@ -78,7 +96,34 @@ class ButtonDebounce extends React.Component {
 }
 
 class PlaybackStatus extends React.Component {
+    componentDidMount() {
+        // Redraw frequently so progress bar moves smoothly between status updates
+        this.timer = setInterval(this.forceUpdate.bind(this), 250);
+    }
+    componentWillUnmount() {
+        clearInterval(this.timer);
+    }
+    currentProgress() {
+        // Extrapolate from when the server fetched the progress, in the server's clock
+        let status = this.props.status;
+        if ((status.state != "Playing" && status.state != "External") || status.sampled_at_ms === null) {
+            return status.progress_ms;
+        }
+        let server_now = Date.now() + (this.props.clockOffset || 0);
+        let elapsed = Math.max(0, server_now - status.sampled_at_ms);
+        return Math.min(status.progress_ms + elapsed, status.song.duration_ms);
+    }
     render() {
+        let closed = this.props.status !== undefined && this.props.status.schedule == "Closed";
+        let quiet = this.props.status !== undefined ? this.props.status.quiet_period : null;
+        let closed_notice = null;
+        if (closed) {
+            closed_notice = <p><small style={{color: "grey"}}>Outside office hours, requests will be played later</small></p>;
+        } else if (quiet) {
+            let ends = new Date(quiet.ends_at_ms).toLocaleTimeString([], {hour: "2-digit", minute: "2-digit"});
+            closed_notice = <p><small style={{color: "grey"}}>Quiet for {quiet.summary} until {ends}</small></p>;
+        }
+
         if (this.props.status === undefined || this.props.status.song === null || this.props.status.progress_ms === null
             || this.props.status.state == 'NeedsSong') {
             return (
@@ -88,19 +133,21 @@ class PlaybackStatus extends React.Component {
                     <div className="progress-bar progress-bar-striped" role="progressbar" style={{ width: "100%" }} aria-valuenow={100} aria-valuemin="0" aria-valuemax="100">
                     </div>
                 </div>
+                {closed_notice ? <div className="card-body">{closed_notice}</div> : null}
             </div>
             );
         }
 
         let paused = this.props.status.state == "Paused";
-        let progress = 100 * (this.props.status.progress_ms / this.props.status.song.duration_ms);
+        let progress_ms = this.currentProgress();
+        let progress = 100 * (progress_ms / this.props.status.song.duration_ms);
 
-        let time_current = formatDuration(this.props.status.progress_ms / 1000);
+        let time_current = formatDuration(progress_ms / 1000);
         let time_duration = formatDuration(this.props.status.song.duration_ms / 1000);
 
         return (
             <div className="card">
-                <img src={this.props.status.song.album_image_url} className="card-img-top" width="286px" alt="Album artwork" />
+                <img src={albumImage(this.props.status.song, 286)} className="card-img-top" width="286px" alt="Album artwork" />
                 <div className="progress">
                     <div className={"progress-bar" + (paused ? " progress-bar-striped" : "")} role="progressbar" style={{ width: progress + "%" }} aria-valuenow={progress} aria-valuemin="0" aria-valuemax="100">
                         <small style={{ color: "black" }} className="justify-content-end d-flex position-absolute w-100">{time_duration}</small>
@@ -108,9 +155,13 @@ class PlaybackStatus extends React.Component {
                     </div>
                 </div>
                 <div className="card-body">
-                    <h5 className="card-title">{this.props.status.song.title}</h5>
-                    <p className="card-text">{this.props.status.song.artist}</p>
-                    <p><small style={{color: "grey"}}> ({this.props.status.state}) {time_current} / {time_duration}</small></p>
+                    <h5 className="card-title">{this.props.status.song.title}{this.props.status.auto_picked ? <span className="badge badge-info ml-1" title="Picked by radio mode, based on recent requests">Auto-picked</span> : null}</h5>
+                    <p className="card-text">{artistNames(this.props.status.song)}</p>
+                    <p className="card-text"><small>{albumDescription(this.props.status.song)}</small></p>
+                    <p><small style={{color: "grey"}}> ({this.props.status.state}) {time_current} / {time_duration}{this.props.status.volume_percent !== null ? " - volume " + this.props.status.volume_percent + "%" : ""}</small></p>
+                    {closed_notice}
+                    {this.props.status.interrupted ? <p><small style={{color: "grey"}}>Interrupted: {this.props.status.interrupted.song.title}</small></p> : null}
+                    <ButtonDebounce className="btn btn-outline-danger btn-sm" content="Vote to skip" callback={() => fetch("/api/skip")} />
                 </div>
             </div>
         );
@@ -121,8 +172,9 @@ class UpcomingListItem extends React.Component {
     render() {
         return (
             <li className="list-group-item">
-                <img src={this.props.song.album_image_url} className="mr-3" alt="Album art" width="32px" />
-                <b>{this.props.song.title}</b> by <b>{this.props.song.artist}</b>
+                <img src={albumImage(this.props.song, 32)} className="mr-3" alt="Album art" width="32px" />
+                <b>{this.props.song.title}</b> by <b>{artistNames(this.props.song)}</b>
+                {this.props.song.requested_by ? <small className="text-muted"> requested by {this.props.song.requested_by}</small> : null}
             </li>
         );
     }
@@ -136,7 +188,7 @@ class UpcomingList extends React.Component {
         if (Object.keys(this.props.queue.songs).length) {
             var body = (
                 <div>
-                    <h2>Upcoming songs, in no particular order:</h2>
+                    <h2>Upcoming songs:</h2>
                     <ul className="list-group">
                         {Object.keys(this.props.queue.songs).map((k) => <UpcomingListItem key={k} song={this.props.queue.songs[k]} />)}
                     </ul>
@@ -160,10 +212,13 @@ class UpcomingList extends React.Component {
     }
 }
 
+// Identifies this page to /api/suggest, so the server can drop queries superseded by newer ones
+const suggestClient = Math.random().toString(36).slice(2);
+
 class SearchWidget extends React.Component {
     constructor(props) {
         super(props);
-        this.state = { value: '', data: [], busy: false };
+        this.state = { value: '', kind: 'track', hide_explicit: false, data: [], search: null, suggestions: null, busy: false };
 
         this.handleChange = this.handleChange.bind(this);
         this.handleSubmit = this.handleSubmit.bind(this);
@@ -185,37 +240,94 @@ class SearchWidget extends React.Component {
         document.removeEventListener("keydown", this.escFunction.bind(this), false);
     }
     clearResults() {
-        this.setState({ data: [] });
+        this.setState({ data: [], search: null });
     }
     handleChange(event) {
         this.clearResults();
-        this.setState({ value: event.target.value });
+        this.setState({ value: event.target.value, suggestions: null });
+        this.suggest(event.target.value);
+    }
+
+    suggest(value) {
+        var params = new URLSearchParams({q: value, client: suggestClient});
+        fetch("/api/suggest?" + params).then(function (resp) {
+            return resp.json();
+        }).then(function (d) {
+            // Ignore answers to older queries
+            if(d.Suggest && !d.Suggest.superseded && d.Suggest.prefix == this.state.value) {
+                this.setState({ suggestions: d.Suggest });
+            }
+        }.bind(this));
     }
     handleSubmit(event) {
-        self = this;
         event.preventDefault();
         this.clearResults();
+        this.search({path: "/search/" + this.state.kind + "/" + encodeURIComponent(this.state.value)}, 0);
+    }
+
+    // Fetch a page of results. Later pages are added to the results already shown
+    search(search, offset) {
+        var params = new URLSearchParams({offset: offset, playable_only: 1});
+        if(this.state.hide_explicit) {
+            params.set("hide_explicit", 1);
+        }
         this.setState({busy: true});
 
-        var u = "/search/track/" + encodeURIComponent(this.state.value);
+        var u = search.path + "?" + params;
         fetch(u).then(function (resp) {
             this.setState({busy: false});
             return resp.json(); // FIXME: Handle error
         }.bind(this)).then(function (d) {
-            self.setState({ data: d });
+            var previous = this.state.data.Search;
+            if(d.Search && previous && offset > 0) {
+                d.Search.items = previous.items.concat(d.Search.items);
+                d.Search.artists = previous.artists.concat(d.Search.artists);
+                d.Search.albums = previous.albums.concat(d.Search.albums);
+            }
+            this.setState({ data: d, search: search });
         }.bind(this));
     }
 
+    moreResults() {
+        this.search(this.state.search, this.state.data.Search.next_offset);
+    }
+
+    // Show an artist's top songs or albums, or an album's songs
+    drillDown(path, event) {
+        event.preventDefault();
+        this.clearResults();
+        this.search({path: path}, 0);
+    }
+
     play(event) {
         event.preventDefault();
         let spotify_uri = event.currentTarget.dataset.spotifyurl;
         var u = "/api/request/" + encodeURIComponent(spotify_uri);
+        let requester = localStorage.getItem("requester");
+        if(requester === null) {
+            requester = prompt("Your name, shown next to your requests (optional)") || "";
+            localStorage.setItem("requester", requester);
+        }
+        if(requester) {
+            u += "?requester=" + encodeURIComponent(requester);
+        }
         this.setState({busy: true});
         fetch(u).then(function (resp) {
             this.cancel();
             return resp.json(); // FIXME: Handle error
         }.bind(this)).then(function (d) {
             console.log("Requested song", d);
+            if(d.Error) {
+                alert("Could not request song: " + d.Error);
+            } else if(d.Request && d.Request.Duplicate) {
+                var dup = d.Request.Duplicate;
+                var where = {List: "already in the list", Playing: "playing now", History: "played recently"}[dup.location];
+                alert(dup.existing.title + " by " + artistNames(dup.existing) + " is " + where);
+            } else if(d.Request && d.Request.Blocked) {
+                alert(d.Request.Blocked.title + " cannot be requested");
+            } else if(d.Request && d.Request.OverQuota) {
+                alert("Could not request song: " + d.Request.OverQuota.reason);
+            }
         }.bind(this));
     }
 
@@ -230,18 +342,51 @@ class SearchWidget extends React.Component {
             );
         }
         // Format search results if any
-        if (this.state.data.Search && this.state.data.Search.items.length > 0) {
+        var results = this.state.data.Search;
+        if (results && results.items.length + results.artists.length + results.albums.length > 0) {
             var sr = <ul className="list-group">
-                {this.state.data.Search.items.map(
+                {results.items.map(
                     (x) => <li className="list-group-item" key={x.spotify_uri}>
                         <a href="#" onClick={this.play.bind(this)} data-spotifyurl={x.spotify_uri}>
-                            <img src={x.album_image_url} width={32} />
-                            <b>{x.title}</b> by <b>{x.artist}</b> ({formatDuration(x.duration_ms / 1000)})
+                            <img src={albumImage(x, 32)} width={32} />
+                            <b>{x.title}</b> by <b>{artistNames(x)}</b>{x.explicit ? <span className="badge badge-secondary ml-1">E</span> : null} ({formatDuration(x.duration_ms / 1000)})
                         </a>
                     </li>)}
+                {results.artists.map(
+                    (a) => <li className="list-group-item" key={a.id}>
+                        <b>{a.name}</b>
+                        <a href="#" className="ml-2" onClick={this.drillDown.bind(this, "/browse/artist/" + a.id + "/top-tracks")}>Top songs</a>
+                        <a href="#" className="ml-2" onClick={this.drillDown.bind(this, "/browse/artist/" + a.id + "/albums")}>Albums</a>
+                    </li>)}
+                {results.albums.map(
+                    (a) => <li className="list-group-item" key={a.id}>
+                        <a href="#" onClick={this.drillDown.bind(this, "/browse/album/" + a.id + "/tracks")}>
+                            <img src={albumImage({album: a}, 32)} width={32} />
+                            <b>{a.name}</b> by <b>{a.artists.map((x) => x.name).join(", ")}</b>{a.release_year ? " (" + a.release_year + ")" : null}
+                        </a>
+                    </li>)}
+                {results.next_offset !== null ?
+                    <li className="list-group-item">
+                        <button className="btn btn-outline-secondary btn-sm" type="button" onClick={this.moreResults.bind(this)}>More results</button>
+                    </li> : null}
             </ul>
-        } else if (this.state.data.Search) {
+        } else if (results) {
             var sr = <div>No results found</div>;
+        } else if (this.state.suggestions && this.state.suggestions.tracks.length + this.state.suggestions.artists.length > 0) {
+            var sr = <ul className="list-group">
+                {this.state.suggestions.tracks.map(
+                    (x) => <li className="list-group-item py-1" key={x.spotify_uri}>
+                        <a href="#" onClick={this.play.bind(this)} data-spotifyurl={x.spotify_uri}>
+                            <b>{x.title}</b> by {artistNames(x)}
+                        </a>
+                    </li>)}
+                {this.state.suggestions.artists.map(
+                    (a) => <li className="list-group-item py-1" key={a.id}>
+                        <a href="#" onClick={this.drillDown.bind(this, "/browse/artist/" + a.id + "/top-tracks")}>
+                            <b>{a.name}</b> (artist)
+                        </a>
+                    </li>)}
+            </ul>
         } else {
             var sr = <span></span>;
         }
@@ -253,6 +398,14 @@ class SearchWidget extends React.Component {
                         <label>
                             Name: <input type="text" value={this.state.value} onChange={this.handleChange} ref={(input) => { this.searchInput = input; }} />
                         </label>
+                        <select value={this.state.kind} onChange={(e) => { this.clearResults(); this.setState({kind: e.target.value}); }}>
+                            <option value="track">Songs</option>
+                            <option value="artist">Artists</option>
+                            <option value="album">Albums</option>
+                        </select>
+                        <label>
+                            <input type="checkbox" checked={this.state.hide_explicit} onChange={(e) => this.setState({hide_explicit: e.target.checked})} /> Hide explicit
+                        </label>
                         {this.state.value.length > 0 ? <input type="submit" value="Search!" className="btn btn-success" /> : <span />}
                     </form>
                     {sr}
@@ -338,6 +491,7 @@ class MainView extends React.Component {
             info: undefined,
             socket: undefined,
             status: undefined,
+            clock_offset: 0,
             queue: undefined,
             is_searching: false,
             playlist_info: undefined,
@@ -393,6 +547,10 @@ class MainView extends React.Component {
             var new_status = data.Status[0];
             this.setState({ status: new_status });
 
+            // Difference between server and browser clocks, for progress extrapolation
+            var server_time = data.Status[2];
+            this.setState({ clock_offset: server_time.now_ms - Date.now() });
+
             var new_playlist_info = data.Status[1];
             if(this.state.queue_info === undefined
                 || this.state.queue_info.playlist_version === 0
@@ -456,7 +614,7 @@ class MainView extends React.Component {
             var body = (
                 <div className="row">
                     <div className="col-md-4">
-                        <PlaybackStatus status={this.state.status} />
+                        <PlaybackStatus status={this.state.status} clockOffset={this.state.clock_offset} />
                     </div>
                     <div className="col-md-8">
                         <UpcomingList queue={this.state.queue} showSearch={this.toggleSearch.bind(this)} />
@@ -467,7 +625,7 @@ class MainView extends React.Component {
         return (
             <ErrorBoundary>
                 <nav className="navbar navbar-dark bg-dark">
-                    <a className="navbar-brand" href="#">Count Jukeula</a>
+                    <a className="navbar-brand" href="#">{document.getElementById("app").dataset.name}</a>
                     <button className={"btn btn-outline-info" + (this.state.is_searching ? ' active' : '')} type="button" onClick={this.toggleSearch.bind(this)}>Add song</button>
                     <span><img src="/static/thejuke.png" width="32px" /></span>
                 </nav>
@@ -475,7 +633,7 @@ class MainView extends React.Component {
                 {body}
                 <p></p>
                 <nav className="navbar navbar-dark bg-dark">
-                    <small>Count Jukeula the Chune Maker. Powered by Spotify. Vampire by Nikita Kozin from the Noun Project</small>
+                    <small>{document.title}. Powered by Spotify. Vampire by Nikita Kozin from the Noun Project</small>
                     <small><a href="#" onClick={this.clearDevice.bind(this)}>Change device</a></small>
                     <small><a href="#" onClick={this.logout.bind(this)}>Disconnect from Spotify</a></small>
                 </nav>